@@ -47,7 +47,7 @@ impl Protocol for EchoClientProtocol {
         }
 
         let input_str = input.as_str();
-        if input_str == "bye\n".to_string() {
+        if input_str == "bye\n" {
             self.running = false;
         }
         println!("{:?}", self.running);
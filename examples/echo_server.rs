@@ -7,7 +7,7 @@ extern crate env_logger;
 
 use std::str;
 
-use janeiro::{Rio, Transport, ServerFactory, Protocol};
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Signal};
 
 
 struct EchoProtocol;
@@ -56,7 +56,7 @@ impl EchoServerFactory {
 
 impl ServerFactory for EchoServerFactory {
 
-    fn build_protocol(&self) -> Box<Protocol> {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
        let proto = EchoProtocol::new();
        Box::new(proto)
     }
@@ -69,7 +69,12 @@ pub fn main() {
     info!("Start the echo server");
     let server = EchoServerFactory::new();
     let mut rio = Rio::new();
-    let _ = rio.listen("0.0.0.0:8888", Box::new(server));
+    let listener = rio.listen("0.0.0.0:8888", Box::new(server)).unwrap();
+    info!("Listening on {}", listener.local_addr());
+    let _ = rio.add_signal_handler(Signal::SIGTERM, Box::new(|rio: &mut Rio| {
+        info!("SIGTERM received, stopping the echo server");
+        rio.stop();
+    }));
     info!("Start running the loop");
     rio.run_forever();
 }
@@ -0,0 +1,126 @@
+//! Adopt listening sockets created by another process.
+//!
+//! With systemd socket activation, the sockets are bound by systemd
+//! and passed to the service from the file descriptor 3, using the
+//! `LISTEN_FDS` and `LISTEN_PID` environment variables.
+//! With `Rio::handover`, the sockets are passed to the new process
+//! using the `JANEIRO_LISTEN_FDS` environment variable.
+//! The file descriptors returned can be passed to `Rio::listen_fd`.
+
+use std::env;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use nix::fcntl::{fcntl, FcntlArg, FD_CLOEXEC};
+use nix::unistd::getpid;
+
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// The environment variable listing the file descriptors passed by
+/// `Rio::handover`.
+pub const HANDOVER_FDS: &str = "JANEIRO_LISTEN_FDS";
+
+
+/// Return the file descriptors passed by systemd socket activation,
+/// an empty list if the process has not been activated by a socket.
+/// If `unset_environment` is true, the environment variables are removed
+/// so the file descriptors are not inherited by child processes.
+pub fn listen_fds(unset_environment: bool) -> io::Result<Vec<RawFd>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    if unset_environment {
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let fds = parse_listen_fds(listen_pid.as_ref().map(|pid| &pid[..]),
+                               listen_fds.as_ref().map(|fds| &fds[..]),
+                               getpid() as u32)?;
+    for fd in &fds {
+        fcntl(*fd, FcntlArg::F_SETFD(FD_CLOEXEC))?;
+    }
+    info!("{} file descriptors inherited from socket activation", fds.len());
+    Ok(fds)
+}
+
+
+/// Return the file descriptors passed by the process that called
+/// `Rio::handover`, an empty list if the process has not been started
+/// by a handover.
+/// If `unset_environment` is true, the environment variable is removed
+/// so the file descriptors are not inherited by child processes.
+pub fn handover_fds(unset_environment: bool) -> io::Result<Vec<RawFd>> {
+    let handover_fds = env::var(HANDOVER_FDS).ok();
+    if unset_environment {
+        env::remove_var(HANDOVER_FDS);
+    }
+
+    let fds = parse_handover_fds(handover_fds.as_ref().map(|fds| &fds[..]))?;
+    for fd in &fds {
+        fcntl(*fd, FcntlArg::F_SETFD(FD_CLOEXEC))?;
+    }
+    info!("{} file descriptors inherited from handover", fds.len());
+    Ok(fds)
+}
+
+
+fn parse_listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> io::Result<Vec<RawFd>> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(Vec::new()),
+    };
+    let listen_pid: u32 = listen_pid.trim().parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid LISTEN_PID"))?;
+    if listen_pid != pid {
+        debug!("LISTEN_PID {} is not for the current process {}", listen_pid, pid);
+        return Ok(Vec::new());
+    }
+    let listen_fds: RawFd = listen_fds.trim().parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid LISTEN_FDS"))?;
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + listen_fds).collect())
+}
+
+
+fn parse_handover_fds(handover_fds: Option<&str>) -> io::Result<Vec<RawFd>> {
+    match handover_fds {
+        Some(handover_fds) => {
+            handover_fds.split(',')
+                .filter(|fd| !fd.trim().is_empty())
+                .map(|fd| {
+                    fd.trim().parse()
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid JANEIRO_LISTEN_FDS"))
+                })
+                .collect()
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{parse_listen_fds, parse_handover_fds};
+
+    #[test]
+    pub fn test_parse_listen_fds() {
+        assert_eq!(parse_listen_fds(Some("42"), Some("2"), 42).unwrap(), vec![3, 4]);
+        assert_eq!(parse_listen_fds(Some("42"), Some("0"), 42).unwrap(), vec![]);
+        assert_eq!(parse_listen_fds(Some("41"), Some("2"), 42).unwrap(), vec![]);
+        assert_eq!(parse_listen_fds(None, Some("2"), 42).unwrap(), vec![]);
+        assert_eq!(parse_listen_fds(Some("42"), None, 42).unwrap(), vec![]);
+        assert!(parse_listen_fds(Some("pid"), Some("2"), 42).is_err());
+        assert!(parse_listen_fds(Some("42"), Some("fds"), 42).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_handover_fds() {
+        assert_eq!(parse_handover_fds(Some("5,7")).unwrap(), vec![5, 7]);
+        assert_eq!(parse_handover_fds(Some("5")).unwrap(), vec![5]);
+        assert_eq!(parse_handover_fds(Some("")).unwrap(), vec![]);
+        assert_eq!(parse_handover_fds(None).unwrap(), vec![]);
+        assert!(parse_handover_fds(Some("5,fd")).is_err());
+    }
+}
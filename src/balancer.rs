@@ -0,0 +1,455 @@
+//! Balance the connections between backends, forwarded with the proxy.
+//!
+//! The backend of a connection is chosen by a `Strategy` among the
+//! healthy backends. The health checks open a TCP connection to every
+//! backend periodically, a backend is ejected after `fall` failed checks
+//! in a row, and admitted again after `rise` successful checks in a row.
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+use interface::{Protocol, Reason, ServerFactory};
+use proxy::ProxyProtocol;
+use rio::{Rio, Timer};
+use transport::Transport;
+
+
+/// Points of every backend on the hash ring.
+const VIRTUAL_NODES: usize = 100;
+
+
+/// How the backend of a new connection is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each backend in turn.
+    RoundRobin,
+    /// The backend with the fewest open connections.
+    LeastConnections,
+    /// The same backend for the same client address, while it is
+    /// healthy. Only the connections of an ejected backend move.
+    ConsistentHash,
+}
+
+
+/// Configuration of the health checks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    interval: Duration,
+    timeout: Duration,
+    rise: usize,
+    fall: usize,
+}
+
+
+impl HealthCheck {
+    /// Check every backend at the interval, with a timeout of
+    /// the interval, a rise of 2 and a fall of 3.
+    pub fn new(interval: Duration) -> HealthCheck {
+        HealthCheck {
+            interval,
+            timeout: interval,
+            rise: 2,
+            fall: 3,
+        }
+    }
+
+    /// The duration after which a connection not established
+    /// is a failed check.
+    pub fn timeout(mut self, timeout: Duration) -> HealthCheck {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The number of successful checks in a row to admit an
+    /// ejected backend again.
+    pub fn rise(mut self, rise: usize) -> HealthCheck {
+        self.rise = rise;
+        self
+    }
+
+    /// The number of failed checks in a row to eject a backend.
+    pub fn fall(mut self, fall: usize) -> HealthCheck {
+        self.fall = fall;
+        self
+    }
+}
+
+
+struct Backend {
+    addr: SocketAddr,
+    healthy: bool,
+    successes: usize,
+    failures: usize,
+    connections: usize,
+}
+
+
+struct Pool {
+    backends: Vec<Backend>,
+    strategy: Strategy,
+    next: usize,
+    // hash of the virtual nodes, and the index of their backend
+    ring: Vec<(u64, usize)>,
+    // the next round of health checks, while they run
+    checks: Option<Timer>,
+}
+
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+impl Pool {
+    fn new(backends: &[SocketAddr], strategy: Strategy) -> Pool {
+        let mut ring = backends.iter()
+            .enumerate()
+            .flat_map(|(idx, addr)| (0..VIRTUAL_NODES).map(move |node| (hash(&(addr, node)), idx)))
+            .collect::<Vec<(u64, usize)>>();
+        ring.sort();
+        Pool {
+            backends: backends.iter()
+                .map(|addr| {
+                    Backend {
+                        addr: *addr,
+                        healthy: true,
+                        successes: 0,
+                        failures: 0,
+                        connections: 0,
+                    }
+                })
+                .collect(),
+            strategy,
+            next: 0,
+            ring,
+            checks: None,
+        }
+    }
+
+    /// The index of the backend for a client, `None` if every
+    /// backend is ejected.
+    fn select(&mut self, client: Option<IpAddr>) -> Option<usize> {
+        let count = self.backends.len();
+        let selected = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.next;
+                let selected = (0..count).map(|offset| (start + offset) % count)
+                    .find(|idx| self.backends[*idx].healthy);
+                if let Some(idx) = selected {
+                    self.next = (idx + 1) % count;
+                }
+                selected
+            }
+            Strategy::LeastConnections => {
+                (0..count).filter(|idx| self.backends[*idx].healthy)
+                    .min_by_key(|idx| self.backends[*idx].connections)
+            }
+            Strategy::ConsistentHash => {
+                let key = client.map(|ip| hash(&ip)).unwrap_or(0);
+                let start = match self.ring.binary_search(&(key, 0)) {
+                    Ok(pos) | Err(pos) => pos,
+                };
+                let ring_len = self.ring.len();
+                (0..ring_len).map(|offset| self.ring[(start + offset) % ring_len].1)
+                    .find(|idx| self.backends[*idx].healthy)
+            }
+        };
+        if let Some(idx) = selected {
+            self.backends[idx].connections += 1;
+        }
+        selected
+    }
+
+    fn release(&mut self, idx: usize) {
+        self.backends[idx].connections -= 1;
+    }
+
+    fn check_done(&mut self, idx: usize, success: bool, check: &HealthCheck) {
+        let backend = &mut self.backends[idx];
+        if success {
+            backend.failures = 0;
+            backend.successes += 1;
+            if !backend.healthy && backend.successes >= check.rise {
+                info!("Backend {} is healthy again", backend.addr);
+                backend.healthy = true;
+            }
+        } else {
+            backend.successes = 0;
+            backend.failures += 1;
+            if backend.healthy && backend.failures >= check.fall {
+                warn!("Backend {} is unhealthy, ejecting it", backend.addr);
+                backend.healthy = false;
+            }
+        }
+    }
+}
+
+
+/// A pool of backends, shared by the factory and the health checks.
+pub struct Balancer {
+    pool: Rc<RefCell<Pool>>,
+}
+
+
+impl Balancer {
+    /// Balance between the backends, all healthy until checked.
+    pub fn new(backends: &[SocketAddr], strategy: Strategy) -> Balancer {
+        Balancer { pool: Rc::new(RefCell::new(Pool::new(backends, strategy))) }
+    }
+
+    /// The factory to pass to `Rio::listen`.
+    pub fn factory(&self) -> BalancerFactory {
+        BalancerFactory { pool: self.pool.clone() }
+    }
+
+    /// Start checking the backends periodically, on the loop, instead
+    /// of the health checks started before.
+    pub fn health_checks(&self, rio: &mut Rio, check: HealthCheck) {
+        self.stop_health_checks(rio);
+        schedule_checks(rio, self.pool.clone(), Rc::new(check), Duration::from_millis(0));
+    }
+
+    /// Stop the health checks, the checks in progress still complete.
+    /// Return false if they were not running.
+    pub fn stop_health_checks(&self, rio: &mut Rio) -> bool {
+        let checks = self.pool.borrow_mut().checks.take();
+        match checks {
+            Some(timer) => rio.cancel_timer(timer),
+            None => false,
+        }
+    }
+
+    /// The backends that are not ejected.
+    pub fn healthy(&self) -> Vec<SocketAddr> {
+        self.pool.borrow().backends.iter()
+            .filter(|backend| backend.healthy)
+            .map(|backend| backend.addr)
+            .collect()
+    }
+
+    /// The number of connections forwarded to the backend.
+    pub fn connections(&self, backend: SocketAddr) -> usize {
+        self.pool.borrow().backends.iter()
+            .filter(|b| b.addr == backend)
+            .map(|b| b.connections)
+            .sum()
+    }
+}
+
+
+fn schedule_checks(rio: &mut Rio, pool: Rc<RefCell<Pool>>, check: Rc<HealthCheck>, delay: Duration) {
+    let checks = pool.clone();
+    let timer = rio.call_later(delay, Box::new(move |rio: &mut Rio| {
+        let addrs = pool.borrow().backends.iter().map(|backend| backend.addr).collect::<Vec<SocketAddr>>();
+        for (idx, addr) in addrs.into_iter().enumerate() {
+            debug!("Checking backend {}", addr);
+            let done = Rc::new(Cell::new(false));
+            let probe = HealthProbe {
+                idx,
+                pool: pool.clone(),
+                check: check.clone(),
+                done: done.clone(),
+            };
+            let token = match rio.connect(&addr.to_string(), Box::new(probe)) {
+                Ok(token) => token,
+                Err(err) => {
+                    error!("Cannot check backend {}: {}", addr, err);
+                    continue;
+                }
+            };
+            let timeout_pool = pool.clone();
+            let timeout_check = check.clone();
+            rio.call_later(check.timeout, Box::new(move |rio: &mut Rio| {
+                // the probe is still open until done, the token is its own
+                if !done.replace(true) {
+                    debug!("Check of backend {} timed out", addr);
+                    timeout_pool.borrow_mut().check_done(idx, false, &timeout_check);
+                    if let Err(err) = rio.hang_up(token) {
+                        error!("Cannot close the check of backend {}: {}", addr, err);
+                    }
+                }
+            }));
+        }
+        let interval = check.interval;
+        schedule_checks(rio, pool, check, interval);
+    }));
+    checks.borrow_mut().checks = Some(timer);
+}
+
+
+/// A connection closed as soon as it is established, or by the loop
+/// once it timed out.
+struct HealthProbe {
+    idx: usize,
+    pool: Rc<RefCell<Pool>>,
+    check: Rc<HealthCheck>,
+    done: Rc<Cell<bool>>,
+}
+
+
+impl Protocol for HealthProbe {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.hang_up();
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        if !self.done.replace(true) {
+            self.pool.borrow_mut().check_done(self.idx, reason == Reason::HangUp, &self.check);
+        }
+    }
+}
+
+
+/// Build a `BalancerProtocol` for every accepted connection.
+pub struct BalancerFactory {
+    pool: Rc<RefCell<Pool>>,
+}
+
+
+impl ServerFactory for BalancerFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(BalancerProtocol {
+            pool: self.pool.clone(),
+            backend: None,
+            proxy: None,
+        })
+    }
+}
+
+
+/// Choose a backend once connected, and forward the connection to it.
+pub struct BalancerProtocol {
+    pool: Rc<RefCell<Pool>>,
+    backend: Option<usize>,
+    proxy: Option<ProxyProtocol>,
+}
+
+
+impl Protocol for BalancerProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        let client = transport.peer_addr().ok().map(|addr| addr.ip());
+        let selected = self.pool.borrow_mut().select(client);
+        match selected {
+            Some(idx) => {
+                let addr = self.pool.borrow().backends[idx].addr;
+                debug!("Forwarding {:?} to the backend {}", client, addr);
+                let mut proxy = ProxyProtocol::new(addr);
+                proxy.connection_made(transport);
+                self.backend = Some(idx);
+                self.proxy = Some(proxy);
+            }
+            None => {
+                error!("No healthy backend for {:?}", client);
+                transport.hang_up();
+            }
+        }
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        if let Some(ref mut proxy) = self.proxy {
+            proxy.data_received(data, transport);
+        }
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        match self.proxy {
+            Some(ref mut proxy) => proxy.eof_received(transport),
+            None => false,
+        }
+    }
+
+    fn pause_writing(&mut self, transport: &mut Transport) {
+        if let Some(ref mut proxy) = self.proxy {
+            proxy.pause_writing(transport);
+        }
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        if let Some(ref mut proxy) = self.proxy {
+            proxy.resume_writing(transport);
+        }
+    }
+
+    fn connection_lost(&mut self, _: Reason) {
+        if let Some(idx) = self.backend.take() {
+            self.pool.borrow_mut().release(idx);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use super::{HealthCheck, Pool, Strategy};
+
+    fn backends() -> Vec<SocketAddr> {
+        ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"].iter()
+            .map(|addr| FromStr::from_str(addr).unwrap())
+            .collect()
+    }
+
+    #[test]
+    pub fn test_round_robin() {
+        let mut pool = Pool::new(&backends(), Strategy::RoundRobin);
+        let selected = (0..4).map(|_| pool.select(None).unwrap()).collect::<Vec<usize>>();
+        assert_eq!(selected, vec![0, 1, 2, 0]);
+    }
+
+    #[test]
+    pub fn test_least_connections() {
+        let mut pool = Pool::new(&backends(), Strategy::LeastConnections);
+        assert_eq!(pool.select(None), Some(0));
+        assert_eq!(pool.select(None), Some(1));
+        assert_eq!(pool.select(None), Some(2));
+        pool.release(1);
+        assert_eq!(pool.select(None), Some(1));
+    }
+
+    #[test]
+    pub fn test_consistent_hash() {
+        let mut pool = Pool::new(&backends(), Strategy::ConsistentHash);
+        let clients = (1..50).map(|i| FromStr::from_str(&format!("192.168.0.{}", i)).unwrap()).collect::<Vec<_>>();
+        let selected = clients.iter().map(|ip| pool.select(Some(*ip)).unwrap()).collect::<Vec<usize>>();
+        assert_eq!(clients.iter().map(|ip| pool.select(Some(*ip)).unwrap()).collect::<Vec<usize>>(), selected);
+        assert!((0..3).all(|idx| selected.contains(&idx)));
+
+        // only the clients of the ejected backend move
+        let check = HealthCheck::new(Duration::from_secs(1)).fall(1);
+        pool.check_done(1, false, &check);
+        for (ip, idx) in clients.iter().zip(selected) {
+            let moved = pool.select(Some(*ip)).unwrap();
+            assert!(moved != 1);
+            if idx != 1 {
+                assert_eq!(moved, idx);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_check_done() {
+        let mut pool = Pool::new(&backends(), Strategy::RoundRobin);
+        let check = HealthCheck::new(Duration::from_secs(1)).rise(2).fall(2);
+        pool.check_done(0, false, &check);
+        assert!(pool.backends[0].healthy);
+        pool.check_done(0, false, &check);
+        assert!(!pool.backends[0].healthy);
+        assert_eq!(pool.select(None), Some(1));
+        assert_eq!(pool.select(None), Some(2));
+        assert_eq!(pool.select(None), Some(1));
+
+        pool.check_done(0, true, &check);
+        assert!(!pool.backends[0].healthy);
+        pool.check_done(0, true, &check);
+        assert!(pool.backends[0].healthy);
+    }
+}
@@ -0,0 +1,26 @@
+use std::time::Instant;
+
+
+/// The source of time of the loop, used to schedule the timers.
+/// The `testing::ManualClock` let tests advance the time instantly.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// True if the time only moves when the program advances it,
+    /// the loop then polls without waiting, instead of sleeping
+    /// until the next timer is due.
+    fn is_virtual(&self) -> bool {
+        false
+    }
+}
+
+
+/// The monotonic clock of the system, used by default.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
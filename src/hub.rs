@@ -0,0 +1,112 @@
+//! Named channels of connections, to broadcast messages between them.
+
+use std::collections::HashMap;
+
+use mio::Token;
+
+
+/// The members of the channels, in the order they joined.
+#[derive(Debug, Default)]
+pub struct Hub {
+    channels: HashMap<String, Vec<Token>>,
+    memberships: HashMap<Token, Vec<String>>,
+}
+
+
+impl Hub {
+    pub fn new() -> Hub {
+        Hub::default()
+    }
+
+    /// Add the connection to the channel, false if it is already a member.
+    pub fn join(&mut self, channel: &str, token: Token) -> bool {
+        let members = self.channels.entry(channel.to_string()).or_default();
+        if members.contains(&token) {
+            return false;
+        }
+        members.push(token);
+        self.memberships.entry(token).or_default().push(channel.to_string());
+        true
+    }
+
+    /// Remove the connection from the channel, false if it is not a member.
+    pub fn leave(&mut self, channel: &str, token: Token) -> bool {
+        let left = match self.channels.get_mut(channel) {
+            Some(members) => {
+                let len = members.len();
+                members.retain(|member| *member != token);
+                members.len() < len
+            }
+            None => false,
+        };
+        if !left {
+            return false;
+        }
+        if self.channels[channel].is_empty() {
+            self.channels.remove(channel);
+        }
+        if let Some(channels) = self.memberships.get_mut(&token) {
+            channels.retain(|joined| joined != channel);
+            if channels.is_empty() {
+                self.memberships.remove(&token);
+            }
+        }
+        true
+    }
+
+    /// Remove the connection from all its channels, once closed.
+    pub fn leave_all(&mut self, token: Token) {
+        for channel in self.memberships.remove(&token).unwrap_or_default() {
+            if let Some(members) = self.channels.get_mut(&channel) {
+                members.retain(|member| *member != token);
+                if members.is_empty() {
+                    self.channels.remove(&channel);
+                }
+            }
+        }
+    }
+
+    /// The members of the channel.
+    pub fn members(&self, channel: &str) -> &[Token] {
+        self.channels.get(channel).map(|members| &members[..]).unwrap_or(&[])
+    }
+
+    pub fn is_member(&self, channel: &str, token: Token) -> bool {
+        self.members(channel).contains(&token)
+    }
+
+    /// The channels joined by the connection.
+    pub fn channels(&self, token: Token) -> &[String] {
+        self.memberships.get(&token).map(|channels| &channels[..]).unwrap_or(&[])
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+
+    use super::Hub;
+
+    #[test]
+    pub fn test_hub() {
+        let mut hub = Hub::new();
+        assert!(hub.join("room", Token(1)));
+        assert!(hub.join("room", Token(2)));
+        assert!(!hub.join("room", Token(1)));
+        assert!(hub.join("other", Token(1)));
+        assert_eq!(hub.members("room"), &[Token(1), Token(2)]);
+        assert_eq!(hub.channels(Token(1)), &["room".to_string(), "other".to_string()]);
+
+        assert!(hub.leave("room", Token(2)));
+        assert!(!hub.leave("room", Token(2)));
+        assert!(!hub.leave("missing", Token(1)));
+        assert_eq!(hub.members("room"), &[Token(1)]);
+
+        hub.leave_all(Token(1));
+        assert!(hub.members("room").is_empty());
+        assert!(hub.members("other").is_empty());
+        assert!(hub.channels(Token(1)).is_empty());
+        assert!(hub.channels.is_empty());
+    }
+}
@@ -1,6 +1,14 @@
-use transport::Transport;
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::process::ExitStatus;
+
+use options::SocketOptions;
+use transport::{Transport, SubprocessTransport};
 
 /// reason of a connection closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Reason {
     /// the connection cannot be opened, no socket listen on the host
     ConnectionError,
@@ -11,6 +19,17 @@ pub enum Reason {
 }
 
 
+/// What a server does with new connections once its limit is reached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Stop accepting, the new connections wait in the backlog until
+    /// a connection is closed.
+    Pause,
+    /// Accept the new connections, write the message and close them.
+    Reject(Vec<u8>),
+}
+
+
 #[allow(unused_variables)]
 /// Instanciate on every connection by a factory, implement your protocol here.
 pub trait Protocol {
@@ -18,20 +37,139 @@ pub trait Protocol {
     /// to write bytes to the connected peer.
     fn connection_made(&mut self, transport: &mut Transport) {}
 
+    /// Call instead of `connection_made` if the connection opened by
+    /// the loop cannot be established, with the error of the socket.
+    /// Call `connection_lost` with `Reason::ConnectionError` by default.
+    fn connection_failed(&mut self, error: &io::Error, transport: &mut Transport) {
+        self.connection_lost(Reason::ConnectionError);
+    }
+
     /// Call everytime a peer sent bytes, use the transport,
     /// to write bytes to the connected peer.
     fn data_received(&mut self, data: &[u8], transport: &mut Transport) {}
 
+    /// Call before reading the socket, return a buffer to receive the
+    /// bytes in, instead of the buffer of the loop. `size_hint` is
+    /// the size of the buffer of the loop.
+    /// If no buffer or an empty one is returned, the bytes are passed
+    /// to the `data_received` method.
+    fn get_buffer(&mut self, size_hint: usize) -> Option<&mut [u8]> {
+        None
+    }
+
+    /// Call everytime bytes have been read in the buffer returned by
+    /// `get_buffer`, `nbytes` is the number of bytes written at the
+    /// start of the buffer.
+    fn buffer_updated(&mut self, nbytes: usize, transport: &mut Transport) {}
+
+    /// Call once the peer shut down its writing side of the connection.
+    /// Return true to keep the connection open, to write to the peer,
+    /// the connection is closed otherwise.
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        false
+    }
+
+    /// Call once the data waiting to be written exceed the high
+    /// watermark of the transport, see `Transport.set_write_buffer_limits`.
+    fn pause_writing(&mut self, transport: &mut Transport) {}
+
+    /// Call once the data waiting to be written are under the low
+    /// watermark of the transport, after `pause_writing`.
+    fn resume_writing(&mut self, transport: &mut Transport) {}
+
+    /// Called when the connection is woken up by `Rio::wake` or
+    /// `Peer::wake`, to write the data queued outside of the protocol
+    /// methods.
+    fn wakeup(&mut self, transport: &mut Transport) {}
+
     /// Call everytime a connection is closed, before the protocol
     /// instance will be destroyed.
     fn connection_lost(&mut self, reason: Reason) {}
 }
 
 
+/// Pass data already read to the protocol, copied in its buffer if it
+/// provides one with `get_buffer`, or to `data_received` otherwise.
+pub fn deliver(protocol: &mut dyn Protocol, data: &[u8], transport: &mut Transport) {
+    let mut data = data;
+    while !data.is_empty() {
+        let nbytes = match protocol.get_buffer(data.len()) {
+            Some(ref mut buf) if !buf.is_empty() => {
+                let nbytes = cmp::min(buf.len(), data.len());
+                buf[..nbytes].copy_from_slice(&data[..nbytes]);
+                nbytes
+            }
+            _ => {
+                protocol.data_received(data, transport);
+                break;
+            }
+        };
+        protocol.buffer_updated(nbytes, transport);
+        data = &data[nbytes..];
+    }
+}
+
+
 /// Used by the ioloop to instanciate a protocol on each new connection.
 pub trait ServerFactory {
     /// Called every time a server socket need to handle a client connection.
     /// There is on instance of protocol per connection, that live until
     /// the connection is closed.
-    fn build_protocol(&self) -> Box<Protocol>;
+    fn build_protocol(&self) -> Box<dyn Protocol>;
+
+    /// Options applied on every accepted socket, before the protocol
+    /// connection_made method is called.
+    fn socket_options(&self) -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// The maximum number of connections accepted by the server and
+    /// open at the same time, unlimited by default.
+    fn max_connections(&self) -> Option<usize> {
+        None
+    }
+
+    /// What to do with new connections once the server or the loop
+    /// reached its maximum number of connections.
+    fn overload_policy(&self) -> OverloadPolicy {
+        OverloadPolicy::Pause
+    }
+
+    /// Called every time the limit is reached, when the server pauses
+    /// or rejects a connection. `connections` is the number of open
+    /// connections accepted by the server.
+    #[allow(unused_variables)]
+    fn limit_reached(&self, connections: usize) {}
+}
+
+
+/// A proxy server to connect through with `Rio::connect_through`,
+/// such as a SOCKS or an HTTP proxy.
+pub trait Tunnel {
+    /// The address of the proxy server.
+    fn addr(&self) -> SocketAddr;
+
+    /// The protocol of the connection to the proxy server. It asks the
+    /// server to connect to the target, then makes the protocol once
+    /// the tunnel is established, or calls its `connection_lost` method
+    /// with `Reason::ConnectionError` if the server refuses.
+    fn tunnel(&self, target: &str, protocol: Box<dyn Protocol>) -> io::Result<Box<dyn Protocol>>;
+}
+
+
+#[allow(unused_variables)]
+/// Instanciate on every spawned process, implement the interaction
+/// with the child process here.
+pub trait SubprocessProtocol {
+    /// Call once the process is spawned, use the transport
+    /// to write bytes to the standard input of the process.
+    fn connection_made(&mut self, transport: &mut SubprocessTransport) {}
+
+    /// Call everytime the process wrote bytes on one of its pipes,
+    /// `fd` is 1 for the standard output and 2 for the standard error.
+    fn pipe_data_received(&mut self, fd: RawFd, data: &[u8], transport: &mut SubprocessTransport) {}
+
+    /// Call once the process has exited and its pipes have been drained,
+    /// before the protocol instance will be destroyed.
+    fn process_exited(&mut self, status: ExitStatus) {}
 }
@@ -1,12 +1,14 @@
 //! A framework for writing network application with a non-blocking IO loop.
 //! Based on the metal io library.
 //!
-//! Currently support TCP.
+//! Currently support TCP, with a proxy built on it, and child processes.
 //!
 //! Totally alpha.
 //!
 
+extern crate futures;
 extern crate mio;
+extern crate net2;
 extern crate nix;
 extern crate slab;
 
@@ -16,8 +18,27 @@ extern crate log;
 mod rio;
 mod transport;
 mod interface;
+mod options;
+mod clock;
+mod hub;
+mod pool;
+mod tunnel;
+pub mod activation;
+pub mod balancer;
+pub mod http_proxy;
+pub mod listener;
+pub mod proxy;
+pub mod proxy_header;
+pub mod redis;
+pub mod socks;
+pub mod stream;
+pub mod testing;
 
 
-pub use interface::{ServerFactory, Protocol, Reason};
-pub use transport::Transport;
-pub use rio::Rio;
+pub use interface::{ServerFactory, OverloadPolicy, Protocol, Reason, SubprocessProtocol, Tunnel};
+pub use transport::{Transport, Peer, SubprocessTransport};
+pub use rio::{Rio, RioBuilder, Stopper, Timer, Waker};
+pub use clock::{Clock, SystemClock};
+pub use listener::{Listener, ListenerBuilder};
+pub use options::{SocketOptions, Keepalive};
+pub use nix::sys::signal::Signal;
@@ -0,0 +1,244 @@
+//! Configure the listening sockets, and share a listening address
+//! between many loops.
+
+use std::io;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+use mio::Token;
+use net2::TcpBuilder;
+use net2::unix::UnixTcpBuilderExt;
+
+use interface::ServerFactory;
+use options;
+use rio::{parse_addr, Rio, Stopper};
+
+
+/// A server registered in the loop by `Rio::listen`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Listener {
+    token: Token,
+    addr: SocketAddr,
+}
+
+
+impl Listener {
+    #[doc(hidden)]
+    pub fn new(token: Token, addr: SocketAddr) -> Listener {
+        Listener { token, addr }
+    }
+
+    /// The token of the server in the loop.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The address the socket is bound to, with the port choosen
+    /// by the system when listening on the port `0`.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+
+/// Options of the listening socket, used by `Rio::listen_with`.
+#[derive(Clone, Debug)]
+pub struct ListenerBuilder {
+    addr: SocketAddr,
+    reuse_address: bool,
+    reuse_port: bool,
+    backlog: i32,
+    only_v6: Option<bool>,
+    device: Option<String>,
+    proxy_protocol: bool,
+}
+
+
+impl ListenerBuilder {
+    /// Options to listen on the given address, with `SO_REUSEADDR`
+    /// and a backlog of 1024 connections.
+    pub fn new(addr: &str) -> io::Result<ListenerBuilder> {
+        Ok(ListenerBuilder {
+            addr: parse_addr(addr)?,
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            only_v6: None,
+            device: None,
+            proxy_protocol: false,
+        })
+    }
+
+    /// The address to listen on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Set `SO_REUSEADDR`, to listen on the address while previous
+    /// connections are still in the `TIME_WAIT` state.
+    pub fn reuse_address(mut self, reuse: bool) -> ListenerBuilder {
+        self.reuse_address = reuse;
+        self
+    }
+
+    /// Set `SO_REUSEPORT`, to let many sockets listen on the same address,
+    /// the kernel distribute the connections between them.
+    pub fn reuse_port(mut self, reuse: bool) -> ListenerBuilder {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// The maximum number of pending connections not accepted yet.
+    pub fn backlog(mut self, backlog: i32) -> ListenerBuilder {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Set `IPV6_V6ONLY`, to not accept IPv4 connections on an IPv6
+    /// address.
+    pub fn only_v6(mut self, only_v6: bool) -> ListenerBuilder {
+        self.only_v6 = Some(only_v6);
+        self
+    }
+
+    /// Set `SO_BINDTODEVICE`, to only accept connections received by
+    /// the given network interface. Requires the `CAP_NET_RAW` capability.
+    pub fn bind_to_device(mut self, device: &str) -> ListenerBuilder {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Read the PROXY protocol header, version 1 or 2, sent by a proxy
+    /// such as HAProxy before the data of every accepted connection.
+    /// The connection is made once the header is read, and closed if
+    /// it is invalid.
+    pub fn proxy_protocol(mut self, proxy_protocol: bool) -> ListenerBuilder {
+        self.proxy_protocol = proxy_protocol;
+        self
+    }
+
+    /// True if the accepted connections start with the PROXY protocol
+    /// header.
+    pub fn has_proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Create the listening socket.
+    pub fn bind(&self) -> io::Result<net::TcpListener> {
+        debug!("Bind the server socket {}", self.addr);
+        let builder = match self.addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+        };
+        builder.reuse_address(self.reuse_address)?;
+        if self.reuse_port {
+            builder.reuse_port(true)?;
+        }
+        if let Some(only_v6) = self.only_v6 {
+            builder.only_v6(only_v6)?;
+        }
+        if let Some(ref device) = self.device {
+            options::bind_to_device(builder.as_raw_fd(), device)?;
+        }
+        builder.bind(self.addr)?;
+        builder.listen(self.backlog)
+    }
+}
+
+
+/// A loop running in its own thread, started by `run_threads`.
+/// The loop keeps running if the handle is dropped.
+pub struct LoopHandle {
+    thread: JoinHandle<()>,
+    stopper: Stopper,
+}
+
+impl LoopHandle {
+    /// Stop the loop, closing its listener and connections,
+    /// then wait for its thread to finish.
+    pub fn shutdown(self) -> thread::Result<()> {
+        // the loop may already be stopped, if it failed to listen
+        let _ = self.stopper.stop();
+        self.thread.join()
+    }
+
+    /// Wait for the thread of the loop to finish.
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+
+/// Run a loop per thread, each one with its own listening socket
+/// using `SO_REUSEPORT` on the same address, so the connections are
+/// distributed between the threads.
+/// The `factory` closure build the ServerFactory of each loop.
+/// The sockets are bound before starting the threads, if the port is `0`,
+/// the port choosen for the first socket is used for the others,
+/// and the bound address is returned.
+pub fn run_threads<F>(threads: usize,
+                      builder: ListenerBuilder,
+                      factory: F)
+                      -> io::Result<(SocketAddr, Vec<LoopHandle>)>
+    where F: Fn() -> Box<dyn ServerFactory> + Send + Sync + 'static
+{
+    let mut builder = builder.reuse_port(true);
+    let mut listeners = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let listener = builder.bind()?;
+        builder.addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    info!("Running {} loops listening on {}", threads, builder.addr);
+
+    let factory = Arc::new(factory);
+    let handles = listeners.into_iter().enumerate().map(|(num, listener)| {
+        let factory = factory.clone();
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(format!("janeiro-{}", num))
+            .spawn(move || {
+                let mut rio = Rio::new();
+                let stopper = rio.stopper();
+                let started = stopper.is_ok();
+                let _ = sender.send(stopper);
+                if !started {
+                    return;
+                }
+                if let Err(err) = rio.listen_fd(listener.into_raw_fd(), factory()) {
+                    error!("Cannot listen in thread {}: {}", num, err);
+                    return;
+                }
+                rio.run_forever();
+                info!("Loop of thread {} stopped", num);
+            })?;
+        let stopper = receiver.recv().map_err(|_| io::Error::other(format!("Cannot start the thread {}", num)))??;
+        Ok(LoopHandle { thread, stopper })
+    }).collect::<io::Result<Vec<LoopHandle>>>()?;
+    Ok((builder.addr, handles))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::ListenerBuilder;
+
+    #[test]
+    pub fn test_reuse_port() {
+        let builder = ListenerBuilder::new("127.0.0.1:0").unwrap().reuse_port(true).backlog(16);
+        let first = builder.bind().unwrap();
+        let addr = first.local_addr().unwrap();
+
+        let builder = ListenerBuilder::new(&addr.to_string()).unwrap();
+        assert!(builder.clone().bind().is_err());
+        let second = builder.reuse_port(true).bind().unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    pub fn test_invalid_address() {
+        assert!(ListenerBuilder::new("localhost").is_err());
+    }
+}
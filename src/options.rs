@@ -0,0 +1,269 @@
+use std::cmp;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use nix::libc;
+
+
+/// TCP keepalive probes configuration, to detect dead peers
+/// of long-lived connections. The delays are rounded up to the second,
+/// and cannot be 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keepalive {
+    idle: Duration,
+    interval: Option<Duration>,
+    count: Option<u32>,
+}
+
+
+impl Keepalive {
+    /// Start sending probes after the connection has been idle
+    /// for the given duration.
+    pub fn new(idle: Duration) -> Keepalive {
+        Keepalive {
+            idle,
+            interval: None,
+            count: None,
+        }
+    }
+
+    /// The duration between two probes.
+    pub fn interval(mut self, interval: Duration) -> Keepalive {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// The number of unanswered probes before dropping the connection.
+    pub fn count(mut self, count: u32) -> Keepalive {
+        self.count = Some(count);
+        self
+    }
+}
+
+
+/// Options applied on a connection socket, before the
+/// `Protocol.connection_made` method is called.
+/// Only the options that have been set are applied.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Keepalive>,
+    linger: Option<Option<Duration>>,
+    ttl: Option<u32>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+
+impl SocketOptions {
+    /// Options leaving the system defaults.
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// Set `TCP_NODELAY`, to disable the Nagle algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Set `SO_KEEPALIVE` and its probes configuration.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> SocketOptions {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Set `SO_LINGER`, the duration the close of the socket wait for
+    /// the unsent data to be sent, rounded up to the second, `None` to
+    /// close in background.
+    pub fn linger(mut self, linger: Option<Duration>) -> SocketOptions {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Set `IP_TTL`, the time-to-live of the sent packets.
+    pub fn ttl(mut self, ttl: u32) -> SocketOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Set `SO_RCVBUF`, the size of the kernel receive buffer.
+    pub fn recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set `SO_SNDBUF`, the size of the kernel send buffer.
+    pub fn send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    #[doc(hidden)]
+    pub fn apply(&self, fd: RawFd) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            set_nodelay(fd, nodelay)?;
+        }
+        if let Some(ref keepalive) = self.keepalive {
+            set_keepalive(fd, Some(keepalive))?;
+        }
+        if let Some(linger) = self.linger {
+            set_linger(fd, linger)?;
+        }
+        if let Some(ttl) = self.ttl {
+            set_ttl(fd, ttl)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            set_recv_buffer_size(fd, size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            set_send_buffer_size(fd, size)?;
+        }
+        Ok(())
+    }
+}
+
+
+fn setsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: T) -> io::Result<()> {
+    setsockopt_raw(fd, level, name, &value as *const T as *const libc::c_void, mem::size_of::<T>())
+}
+
+
+fn setsockopt_raw(fd: RawFd,
+                  level: libc::c_int,
+                  name: libc::c_int,
+                  value: *const libc::c_void,
+                  len: usize)
+                  -> io::Result<()> {
+    let result = unsafe { libc::setsockopt(fd, level, name, value, len as libc::socklen_t) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+/// The duration in seconds, rounded up not to disable the option
+/// with a sub-second duration.
+fn as_secs(duration: Duration) -> libc::c_int {
+    let secs = duration.as_secs() + (duration.subsec_nanos() > 0) as u64;
+    cmp::min(secs, libc::c_int::MAX as u64) as libc::c_int
+}
+
+
+/// The duration in seconds of a keepalive delay, that cannot be 0.
+fn keepalive_secs(duration: Duration) -> io::Result<libc::c_int> {
+    match as_secs(duration) {
+        0 => Err(io::Error::new(io::ErrorKind::InvalidInput, "The keepalive delays cannot be 0")),
+        secs => Ok(secs),
+    }
+}
+
+
+pub fn set_nodelay(fd: RawFd, nodelay: bool) -> io::Result<()> {
+    setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, nodelay as libc::c_int)
+}
+
+
+pub fn set_keepalive(fd: RawFd, keepalive: Option<&Keepalive>) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, keepalive.is_some() as libc::c_int)?;
+    if let Some(keepalive) = keepalive {
+        setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, keepalive_secs(keepalive.idle)?)?;
+        if let Some(interval) = keepalive.interval {
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, keepalive_secs(interval)?)?;
+        }
+        if let Some(count) = keepalive.count {
+            setsockopt(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, count as libc::c_int)?;
+        }
+    }
+    Ok(())
+}
+
+
+pub fn set_linger(fd: RawFd, linger: Option<Duration>) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: linger.is_some() as libc::c_int,
+        l_linger: linger.map(as_secs).unwrap_or(0),
+    };
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_LINGER, linger)
+}
+
+
+pub fn set_ttl(fd: RawFd, ttl: u32) -> io::Result<()> {
+    setsockopt(fd, libc::IPPROTO_IP, libc::IP_TTL, ttl as libc::c_int)
+}
+
+
+pub fn set_recv_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)
+}
+
+
+pub fn set_send_buffer_size(fd: RawFd, size: usize) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)
+}
+
+
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(fd: RawFd, device: &str) -> io::Result<()> {
+    setsockopt_raw(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE, device.as_ptr() as *const libc::c_void, device.len())
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_device(_: RawFd, _: &str) -> io::Result<()> {
+    Err(io::Error::other("SO_BINDTODEVICE is not supported"))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use net2::TcpStreamExt;
+
+    use super::{SocketOptions, Keepalive};
+
+    #[test]
+    pub fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let options = SocketOptions::new()
+            .nodelay(true)
+            .keepalive(Keepalive::new(Duration::from_secs(30)).interval(Duration::from_secs(5)).count(3))
+            .linger(Some(Duration::from_secs(1)))
+            .ttl(42);
+        options.apply(stream.as_raw_fd()).unwrap();
+
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.ttl().unwrap(), 42);
+        assert_eq!(TcpStreamExt::keepalive(&stream).unwrap(), Some(Duration::from_secs(30)));
+        assert_eq!(TcpStreamExt::linger(&stream).unwrap(), Some(Duration::from_secs(1)));
+
+        SocketOptions::new().nodelay(false).apply(stream.as_raw_fd()).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[test]
+    pub fn test_sub_second_durations() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        SocketOptions::new()
+            .keepalive(Keepalive::new(Duration::from_millis(1500)))
+            .linger(Some(Duration::from_millis(100)))
+            .apply(stream.as_raw_fd())
+            .unwrap();
+        assert_eq!(TcpStreamExt::keepalive(&stream).unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(TcpStreamExt::linger(&stream).unwrap(), Some(Duration::from_secs(1)));
+
+        let zero = SocketOptions::new().keepalive(Keepalive::new(Duration::from_secs(0)));
+        assert_eq!(zero.apply(stream.as_raw_fd()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
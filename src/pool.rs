@@ -0,0 +1,188 @@
+//! A pool of threads, to run the blocking work outside of the loop,
+//! such as hashing a password or querying a blocking database driver.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use mio::Token;
+use mio::channel;
+
+
+type Output = Box<dyn Any + Send>;
+type Work = Box<dyn FnOnce() -> Output + Send>;
+type Completion = Box<dyn FnOnce(Output)>;
+type Results = channel::Receiver<(usize, thread::Result<Output>)>;
+
+
+/// The work to run on the pool, and the callback of its result,
+/// called on the loop thread.
+#[doc(hidden)]
+pub struct Job {
+    work: Work,
+    completion: Completion,
+}
+
+
+impl Job {
+    pub fn new<W, T, C>(work: W, callback: C) -> Job
+        where W: FnOnce() -> T + Send + 'static,
+              T: Send + 'static,
+              C: FnOnce(T) + 'static
+    {
+        Job {
+            work: Box::new(move || Box::new(work()) as Output),
+            completion: Box::new(move |output: Output| {
+                match output.downcast::<T>() {
+                    Ok(result) => callback(*result),
+                    Err(_) => unreachable!(),
+                }
+            }),
+        }
+    }
+}
+
+
+/// The threads running the jobs, their results are received
+/// on the loop through a channel.
+pub struct ThreadPool {
+    jobs: mpsc::Sender<(usize, Work)>,
+    results: Results,
+    // the completions of the jobs running, by id
+    pending: HashMap<usize, (Token, Completion)>,
+    next_id: usize,
+}
+
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> io::Result<ThreadPool> {
+        let (jobs, queue) = mpsc::channel::<(usize, Work)>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (sender, results) = channel::channel();
+        for i in 0..threads {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::Builder::new().name(format!("rio-executor-{}", i)).spawn(move || loop {
+                // the loop dropped the pool once the queue is closed
+                let job = queue.lock().unwrap().recv();
+                let (id, work) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                let output = panic::catch_unwind(AssertUnwindSafe(work));
+                if sender.send((id, output)).is_err() {
+                    break;
+                }
+            })?;
+        }
+        Ok(ThreadPool {
+            jobs,
+            results,
+            pending: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// The channel of the results, to register on the loop.
+    pub fn results(&self) -> &Results {
+        &self.results
+    }
+
+    /// Run the job for the connection.
+    pub fn submit(&mut self, token: Token, job: Job) -> io::Result<()> {
+        let id = self.next_id;
+        self.jobs.send((id, job.work))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The executor is stopped"))?;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.insert(id, (token, job.completion));
+        Ok(())
+    }
+
+    /// Drop the results of the jobs of the connection, once closed.
+    pub fn cancel(&mut self, token: Token) {
+        self.pending.retain(|_, (owner, _)| *owner != token);
+    }
+
+    /// The results received of the jobs not cancelled, with the connection
+    /// and the callback to call with them.
+    pub fn take_results(&mut self) -> Vec<(Token, Completion, Output)> {
+        let mut results = Vec::new();
+        while let Ok((id, output)) = self.results.try_recv() {
+            let (token, completion) = match self.pending.remove(&id) {
+                Some(pending) => pending,
+                None => {
+                    debug!("Dropping the result of the job {}, cancelled", id);
+                    continue;
+                }
+            };
+            match output {
+                Ok(output) => results.push((token, completion, output)),
+                Err(_) => error!("The job {} of {:?} panicked", id, token),
+            }
+        }
+        results
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    use mio::Token;
+
+    use super::{Job, ThreadPool};
+
+    fn wait_results(pool: &mut ThreadPool, count: usize) {
+        let mut results = Vec::new();
+        while results.len() < count {
+            results.extend(pool.take_results());
+            thread::sleep(Duration::from_millis(5));
+        }
+        for (_, completion, output) in results {
+            completion(output);
+        }
+    }
+
+    #[test]
+    pub fn test_thread_pool() {
+        let mut pool = ThreadPool::new(2).unwrap();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        for i in 0..4 {
+            let results = results.clone();
+            pool.submit(Token(i), Job::new(move || i * 2, move |result| results.borrow_mut().push(result)))
+                .unwrap();
+        }
+        wait_results(&mut pool, 4);
+        results.borrow_mut().sort();
+        assert_eq!(*results.borrow(), vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    pub fn test_cancel() {
+        let mut pool = ThreadPool::new(1).unwrap();
+        let results = Rc::new(RefCell::new(Vec::new()));
+        for i in 0..3 {
+            let results = results.clone();
+            pool.submit(Token(i), Job::new(move || {
+                if i == 2 {
+                    panic!("Cannot run the job");
+                }
+                i
+            }, move |result| results.borrow_mut().push(result))).unwrap();
+        }
+        pool.cancel(Token(0));
+        let done = results.clone();
+        pool.submit(Token(3), Job::new(|| 3, move |result| done.borrow_mut().push(result))).unwrap();
+        // the pool survives the panic of a job
+        wait_results(&mut pool, 2);
+        assert_eq!(*results.borrow(), vec![1, 3]);
+        assert!(pool.pending.is_empty());
+    }
+}
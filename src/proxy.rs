@@ -0,0 +1,146 @@
+//! Forward the connections to a backend, in both directions.
+//!
+//! For each accepted connection, a connection to the backend is opened
+//! on the same loop, linked to it: when one is closed, the other is
+//! closed once its pending data has been written.
+//! The end of file is forwarded, and the reading of a side is paused
+//! while the other side has too much data waiting to be written.
+//! The addresses of the client can be passed to the backend with
+//! the PROXY protocol header.
+
+use std::io;
+use std::net::SocketAddr;
+
+use interface::{Protocol, ServerFactory};
+use proxy_header::ProxyVersion;
+use rio::parse_addr;
+use transport::Transport;
+
+
+/// Build a `ProxyProtocol` forwarding every accepted connection
+/// to the backend.
+pub struct ProxyFactory {
+    backend: SocketAddr,
+    proxy_header: Option<ProxyVersion>,
+}
+
+
+impl ProxyFactory {
+    /// Forward the connections to the backend address.
+    pub fn new(backend: &str) -> io::Result<ProxyFactory> {
+        let backend = parse_addr(backend)?;
+        Ok(ProxyFactory {
+            backend,
+            proxy_header: None,
+        })
+    }
+
+    /// Write the PROXY protocol header, with the addresses of the
+    /// client, before the data forwarded to the backend.
+    pub fn proxy_header(mut self, version: ProxyVersion) -> ProxyFactory {
+        self.proxy_header = Some(version);
+        self
+    }
+
+    /// The address of the backend.
+    pub fn backend(&self) -> SocketAddr {
+        self.backend
+    }
+}
+
+
+impl ServerFactory for ProxyFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        let protocol = ProxyProtocol::new(self.backend);
+        match self.proxy_header {
+            Some(version) => Box::new(protocol.proxy_header(version)),
+            None => Box::new(protocol),
+        }
+    }
+}
+
+
+/// Forward the bytes between a connection and the one linked to it.
+pub struct ProxyProtocol {
+    backend: Option<SocketAddr>,
+    proxy_header: Option<ProxyVersion>,
+    // received before the connection was opened
+    early_data: Vec<u8>,
+}
+
+
+impl ProxyProtocol {
+    /// Connect to the backend once the connection is made, and
+    /// forward the bytes between them.
+    pub fn new(backend: SocketAddr) -> ProxyProtocol {
+        ProxyProtocol {
+            backend: Some(backend),
+            proxy_header: None,
+            early_data: Vec::new(),
+        }
+    }
+
+    /// Write the PROXY protocol header to the backend first.
+    pub fn proxy_header(mut self, version: ProxyVersion) -> ProxyProtocol {
+        self.proxy_header = Some(version);
+        self
+    }
+
+    /// The side connected to the backend, opened by the linked
+    /// connection that already received the data to write first.
+    pub fn backend_side(early_data: Vec<u8>) -> ProxyProtocol {
+        ProxyProtocol {
+            backend: None,
+            proxy_header: None,
+            early_data,
+        }
+    }
+}
+
+
+impl Protocol for ProxyProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        if let Some(backend) = self.backend.take() {
+            debug!("Forwarding {:?} to {}", transport.token(), backend);
+            let backend_side = Box::new(ProxyProtocol::backend_side(Vec::new()));
+            match self.proxy_header {
+                Some(version) => transport.connect_proxied(backend, backend_side, version),
+                None => transport.connect(backend, backend_side),
+            }
+        } else if !self.early_data.is_empty() {
+            transport.write_owned(self.early_data.split_off(0));
+        }
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        match transport.linked() {
+            Some(peer) => transport.peer(peer).write(data),
+            None => {
+                debug!("No peer to forward {} bytes to, hanging up", data.len());
+                transport.hang_up();
+            }
+        }
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        match transport.linked() {
+            Some(peer) => {
+                transport.peer(peer).write_eof();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pause_writing(&mut self, transport: &mut Transport) {
+        if let Some(peer) = transport.linked() {
+            transport.peer(peer).pause_reading();
+        }
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        if let Some(peer) = transport.linked() {
+            transport.peer(peer).resume_reading();
+        }
+    }
+}
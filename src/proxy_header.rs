@@ -0,0 +1,304 @@
+//! The PROXY protocol of HAProxy, to pass the addresses of the original
+//! connection to the upstream servers.
+//!
+//! A listener built with `ListenerBuilder::proxy_protocol` reads the
+//! header, in the text version 1 or the binary version 2, before
+//! the connection is made, and `Transport::peer_addr` returns the
+//! source address it carries. `Transport::connect_proxied` writes the
+//! header before anything else on the new connection.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest header of the version 1, with the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+
+/// The version of the header to write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyVersion {
+    /// The human readable text version.
+    V1,
+    /// The binary version.
+    V2,
+}
+
+
+/// The addresses of a proxied connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// The connection was accepted by the proxy from the source address,
+    /// on the destination address.
+    Proxy {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// The connection is not proxied, such as the health checks of
+    /// the proxy, or its addresses are not known.
+    Local,
+}
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid PROXY protocol header: {}", message))
+}
+
+
+impl ProxyHeader {
+    /// The header of a connection accepted from the source address,
+    /// on the destination address.
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> ProxyHeader {
+        ProxyHeader::Proxy {
+            source,
+            destination,
+        }
+    }
+
+    /// The source address of a proxied connection.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match *self {
+            ProxyHeader::Proxy { source, .. } => Some(source),
+            ProxyHeader::Local => None,
+        }
+    }
+
+    /// Parse the header at the start of the data, of any version.
+    /// Return the header and its length, or `None` if the data is
+    /// too short to contain it.
+    pub fn parse(data: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+        if starts_with(data, V2_SIGNATURE) {
+            parse_v2(data)
+        } else if starts_with(data, V1_PREFIX) {
+            parse_v1(data)
+        } else {
+            Err(invalid("no signature"))
+        }
+    }
+
+    /// The header to write before the data of the connection.
+    pub fn encode(&self, version: ProxyVersion) -> Vec<u8> {
+        match version {
+            ProxyVersion::V1 => self.encode_v1(),
+            ProxyVersion::V2 => self.encode_v2(),
+        }
+    }
+
+    fn encode_v1(&self) -> Vec<u8> {
+        match *self {
+            ProxyHeader::Proxy { source, destination } => {
+                let (family, source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                        ("TCP4", IpAddr::V4(source_ip), IpAddr::V4(destination_ip))
+                    }
+                    (source_ip, destination_ip) => ("TCP6", IpAddr::V6(to_ipv6(source_ip)), IpAddr::V6(to_ipv6(destination_ip))),
+                };
+                format!("PROXY {} {} {} {} {}\r\n",
+                        family,
+                        source_ip,
+                        destination_ip,
+                        source.port(),
+                        destination.port())
+                    .into_bytes()
+            }
+            ProxyHeader::Local => b"PROXY UNKNOWN\r\n".to_vec(),
+        }
+    }
+
+    fn encode_v2(&self) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        match *self {
+            ProxyHeader::Proxy { source, destination } => {
+                header.push(V2_PROXY);
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                        header.push(V2_TCP4);
+                        header.extend_from_slice(&12u16.to_be_bytes());
+                        header.extend_from_slice(&source_ip.octets());
+                        header.extend_from_slice(&destination_ip.octets());
+                    }
+                    (source_ip, destination_ip) => {
+                        header.push(V2_TCP6);
+                        header.extend_from_slice(&36u16.to_be_bytes());
+                        header.extend_from_slice(&to_ipv6(source_ip).octets());
+                        header.extend_from_slice(&to_ipv6(destination_ip).octets());
+                    }
+                }
+                header.extend_from_slice(&source.port().to_be_bytes());
+                header.extend_from_slice(&destination.port().to_be_bytes());
+            }
+            ProxyHeader::Local => {
+                header.push(V2_LOCAL);
+                header.push(0);
+                header.extend_from_slice(&0u16.to_be_bytes());
+            }
+        }
+        header
+    }
+}
+
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+
+/// True if the data starts with the prefix, or is the start of it.
+fn starts_with(data: &[u8], prefix: &[u8]) -> bool {
+    let len = data.len().min(prefix.len());
+    data[..len] == prefix[..len]
+}
+
+
+fn parse_v1(data: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let end = match data.windows(2).take(V1_MAX_LEN - 1).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if data.len() >= V1_MAX_LEN => return Err(invalid("line too long")),
+        None => return Ok(None),
+    };
+    let line = str::from_utf8(&data[..end]).map_err(|_| invalid("not ascii"))?;
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    let header = match fields[1..] {
+        ["UNKNOWN", ..] => ProxyHeader::Local,
+        [family, source_ip, destination_ip, source_port, destination_port] => {
+            let source_ip = parse_ip(family, source_ip)?;
+            let destination_ip = parse_ip(family, destination_ip)?;
+            let source_port = u16::from_str(source_port).map_err(|_| invalid("invalid port"))?;
+            let destination_port = u16::from_str(destination_port).map_err(|_| invalid("invalid port"))?;
+            ProxyHeader::new(SocketAddr::new(source_ip, source_port),
+                             SocketAddr::new(destination_ip, destination_port))
+        }
+        _ => return Err(invalid("wrong number of fields")),
+    };
+    Ok(Some((header, end + 2)))
+}
+
+
+fn parse_ip(family: &str, ip: &str) -> io::Result<IpAddr> {
+    match family {
+        "TCP4" => Ipv4Addr::from_str(ip).map(IpAddr::V4).map_err(|_| invalid("invalid IPv4 address")),
+        "TCP6" => Ipv6Addr::from_str(ip).map(IpAddr::V6).map_err(|_| invalid("invalid IPv6 address")),
+        _ => Err(invalid("unknown protocol")),
+    }
+}
+
+
+fn parse_v2(data: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if data.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = V2_HEADER_LEN + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < len {
+        return Ok(None);
+    }
+    let addresses = &data[V2_HEADER_LEN..len];
+    let header = match data[12] {
+        V2_LOCAL => ProxyHeader::Local,
+        V2_PROXY => {
+            match data[13] {
+                V2_TCP4 if addresses.len() >= 12 => {
+                    let mut source_ip = [0; 4];
+                    let mut destination_ip = [0; 4];
+                    source_ip.copy_from_slice(&addresses[0..4]);
+                    destination_ip.copy_from_slice(&addresses[4..8]);
+                    ProxyHeader::new(SocketAddr::new(IpAddr::from(source_ip), port(&addresses[8..10])),
+                                     SocketAddr::new(IpAddr::from(destination_ip), port(&addresses[10..12])))
+                }
+                V2_TCP6 if addresses.len() >= 36 => {
+                    let mut source_ip = [0; 16];
+                    let mut destination_ip = [0; 16];
+                    source_ip.copy_from_slice(&addresses[0..16]);
+                    destination_ip.copy_from_slice(&addresses[16..32]);
+                    ProxyHeader::new(SocketAddr::new(IpAddr::from(source_ip), port(&addresses[32..34])),
+                                     SocketAddr::new(IpAddr::from(destination_ip), port(&addresses[34..36])))
+                }
+                V2_TCP4 | V2_TCP6 => return Err(invalid("addresses too short")),
+                // UDP, unix sockets and unspecified are not supported
+                _ => ProxyHeader::Local,
+            }
+        }
+        _ => return Err(invalid("unknown version or command")),
+    };
+    Ok(Some((header, len)))
+}
+
+
+fn port(data: &[u8]) -> u16 {
+    u16::from_be_bytes([data[0], data[1]])
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use super::{ProxyHeader, ProxyVersion};
+
+    fn header(source: &str, destination: &str) -> ProxyHeader {
+        ProxyHeader::new(FromStr::from_str(source).unwrap(), FromStr::from_str(destination).unwrap())
+    }
+
+    #[test]
+    pub fn test_v1() {
+        let data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n";
+        let expected = header("192.168.0.1:56324", "192.168.0.11:443");
+        assert_eq!(ProxyHeader::parse(data).unwrap(), Some((expected, 47)));
+        assert_eq!(expected.encode(ProxyVersion::V1), &data[..47]);
+
+        let data = b"PROXY TCP6 ::1 ::2 1 2\r\n";
+        assert_eq!(ProxyHeader::parse(data).unwrap(), Some((header("[::1]:1", "[::2]:2"), data.len())));
+        let data = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(ProxyHeader::parse(data).unwrap(), Some((ProxyHeader::Local, data.len())));
+        assert_eq!(ProxyHeader::Local.encode(ProxyVersion::V1), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    pub fn test_v2() {
+        for expected in &[header("10.0.0.1:1234", "10.0.0.2:80"), header("[::1]:1234", "[::2]:80"), ProxyHeader::Local] {
+            let mut data = expected.encode(ProxyVersion::V2);
+            let len = data.len();
+            data.extend_from_slice(b"hello");
+            assert_eq!(ProxyHeader::parse(&data[..]).unwrap(), Some((*expected, len)));
+        }
+        // mixed families are sent as IPv6
+        let mixed = header("10.0.0.1:1234", "[::2]:80");
+        let source: SocketAddr = FromStr::from_str("[::ffff:10.0.0.1]:1234").unwrap();
+        let (parsed, _) = ProxyHeader::parse(&mixed.encode(ProxyVersion::V2)[..]).unwrap().unwrap();
+        assert_eq!(parsed.source(), Some(source));
+    }
+
+    #[test]
+    pub fn test_incomplete() {
+        let data = header("10.0.0.1:1234", "10.0.0.2:80").encode(ProxyVersion::V2);
+        for len in 0..data.len() {
+            assert_eq!(ProxyHeader::parse(&data[..len]).unwrap(), None);
+        }
+        assert_eq!(ProxyHeader::parse(b"PROX").unwrap(), None);
+        assert_eq!(ProxyHeader::parse(b"PROXY TCP4 10.0.0.1").unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_invalid() {
+        assert!(ProxyHeader::parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 1\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 ::1 ::2 1 2\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 1 99999\r\n").is_err());
+        assert!(ProxyHeader::parse(&vec![b'X'; 200][..]).is_err());
+        let mut long = b"PROXY ".to_vec();
+        long.extend_from_slice(&vec![b'1'; 200][..]);
+        assert!(ProxyHeader::parse(&long[..]).is_err());
+    }
+}
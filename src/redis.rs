@@ -0,0 +1,758 @@
+//! A client of Redis, for the RESP2 and RESP3 protocols.
+//!
+//! The commands are pipelined: they are written as soon as possible,
+//! and their callbacks are called with the replies, in order. Once the
+//! client subscribed to a channel, the messages are passed to the
+//! handler set with `RedisClient::on_message`.
+//!
+//! The commands queued outside of a callback of the client are written
+//! once the connection is woken up, with `RedisClient::flush` or
+//! `Peer::wake`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+
+use mio::Token;
+
+use interface::{Protocol, Reason};
+use rio::Rio;
+use transport::Transport;
+
+
+/// A value of the RESP protocols, the types after `Null` are RESP3 only.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Vec<u8>),
+    Array(Vec<Value>),
+    Null,
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// The format, such as `txt` or `mkd`, and the text.
+    Verbatim(String, Vec<u8>),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    /// Data sent by the server without a command, such as the messages
+    /// of the channels.
+    Push(Vec<Value>),
+}
+
+
+impl Value {
+    /// Append the value to the buffer.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            Value::SimpleString(ref value) => buf.extend_from_slice(format!("+{}\r\n", value).as_bytes()),
+            Value::Error(ref value) => buf.extend_from_slice(format!("-{}\r\n", value).as_bytes()),
+            Value::Integer(value) => buf.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Value::BulkString(ref value) => {
+                buf.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                buf.extend_from_slice(value);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Array(ref values) => encode_aggregate(buf, b'*', values),
+            Value::Null => buf.extend_from_slice(b"_\r\n"),
+            Value::Double(value) => {
+                let value = if value.is_nan() {
+                    "nan".to_string()
+                } else if value.is_infinite() {
+                    if value > 0.0 { "inf" } else { "-inf" }.to_string()
+                } else {
+                    value.to_string()
+                };
+                buf.extend_from_slice(format!(",{}\r\n", value).as_bytes());
+            }
+            Value::Boolean(value) => buf.extend_from_slice(if value { b"#t\r\n" } else { b"#f\r\n" }),
+            Value::BigNumber(ref value) => buf.extend_from_slice(format!("({}\r\n", value).as_bytes()),
+            Value::Verbatim(ref format, ref text) => {
+                buf.extend_from_slice(format!("={}\r\n{}:", text.len() + 4, format).as_bytes());
+                buf.extend_from_slice(text);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Map(ref pairs) => {
+                buf.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes());
+                for (key, value) in pairs {
+                    key.encode(buf);
+                    value.encode(buf);
+                }
+            }
+            Value::Set(ref values) => encode_aggregate(buf, b'~', values),
+            Value::Push(ref values) => encode_aggregate(buf, b'>', values),
+        }
+    }
+
+    /// The bytes of a simple, bulk or verbatim string.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::SimpleString(ref value) => Some(value.as_bytes()),
+            Value::BulkString(ref value) | Value::Verbatim(_, ref value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+
+fn encode_aggregate(buf: &mut Vec<u8>, kind: u8, values: &[Value]) {
+    buf.push(kind);
+    buf.extend_from_slice(format!("{}\r\n", values.len()).as_bytes());
+    for value in values {
+        value.encode(buf);
+    }
+}
+
+
+/// Encode a command, as an array of bulk strings.
+pub fn encode_command<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let arg = arg.as_ref();
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+
+/// The deepest nesting of the aggregates decoded, the aggregates
+/// received in part are bounded by it.
+const MAX_DEPTH: usize = 64;
+
+/// The longest string decoded, as the default limit of the server.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid RESP data: {}", message))
+}
+
+
+/// The line starting at the position, and the position after its CRLF.
+fn read_line(buf: &[u8], pos: usize) -> io::Result<Option<(&str, usize)>> {
+    match buf[pos..].windows(2).position(|window| window == b"\r\n") {
+        Some(len) => {
+            let line = str::from_utf8(&buf[pos..pos + len]).map_err(|_| invalid("not utf-8"))?;
+            Ok(Some((line, pos + len + 2)))
+        }
+        None => Ok(None),
+    }
+}
+
+
+fn parse_int(line: &str) -> io::Result<i64> {
+    i64::from_str(line).map_err(|_| invalid("invalid integer"))
+}
+
+
+/// A value, or the header of an aggregate and the number of its elements.
+enum Item {
+    Value(Value),
+    Aggregate(u8, i64),
+}
+
+
+/// Parse the value or the header of the aggregate starting at the
+/// position, return it with the position after it, `None` if the
+/// buffer is too short.
+fn parse(buf: &[u8], pos: usize) -> io::Result<Option<(Item, usize)>> {
+    if pos >= buf.len() {
+        return Ok(None);
+    }
+    let kind = buf[pos];
+    let (line, next) = match read_line(buf, pos + 1)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let value = match kind {
+        b'+' => Value::SimpleString(line.to_string()),
+        b'-' => Value::Error(line.to_string()),
+        b':' => Value::Integer(parse_int(line)?),
+        b'_' => Value::Null,
+        b',' => {
+            let value = match line {
+                "inf" => f64::INFINITY,
+                "-inf" => f64::NEG_INFINITY,
+                "nan" => f64::NAN,
+                _ => f64::from_str(line).map_err(|_| invalid("invalid double"))?,
+            };
+            Value::Double(value)
+        }
+        b'#' => {
+            match line {
+                "t" => Value::Boolean(true),
+                "f" => Value::Boolean(false),
+                _ => return Err(invalid("invalid boolean")),
+            }
+        }
+        b'(' => Value::BigNumber(line.to_string()),
+        b'$' | b'!' | b'=' => {
+            let len = parse_int(line)?;
+            if len < 0 {
+                return Ok(Some((Item::Value(Value::Null), next)));
+            }
+            if len > MAX_BULK_LEN {
+                return Err(invalid("string too long"));
+            }
+            let end = next + len as usize;
+            if buf.len() < end + 2 {
+                return Ok(None);
+            }
+            if &buf[end..end + 2] != b"\r\n" {
+                return Err(invalid("missing CRLF"));
+            }
+            let data = buf[next..end].to_vec();
+            let value = match kind {
+                b'$' => Value::BulkString(data),
+                b'!' => Value::Error(String::from_utf8_lossy(&data).into_owned()),
+                _ => {
+                    if data.len() < 4 || data[3] != b':' {
+                        return Err(invalid("invalid verbatim string"));
+                    }
+                    let format = String::from_utf8_lossy(&data[..3]).into_owned();
+                    Value::Verbatim(format, data[4..].to_vec())
+                }
+            };
+            return Ok(Some((Item::Value(value), end + 2)));
+        }
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let count = parse_int(line)?;
+            if count < 0 {
+                return Ok(Some((Item::Value(Value::Null), next)));
+            }
+            let elements = match kind {
+                b'%' => count.checked_mul(2),
+                // the value the attributes are about follows them
+                b'|' => count.checked_mul(2).and_then(|elements| elements.checked_add(1)),
+                _ => Some(count),
+            };
+            let elements = elements.ok_or_else(|| invalid("too many elements"))?;
+            return Ok(Some((Item::Aggregate(kind, elements), next)));
+        }
+        _ => return Err(invalid("unknown type")),
+    };
+    Ok(Some((Item::Value(value), next)))
+}
+
+
+/// The aggregate of the kind with its elements.
+fn aggregate(kind: u8, mut values: Vec<Value>) -> Value {
+    match kind {
+        b'*' => Value::Array(values),
+        b'~' => Value::Set(values),
+        b'>' => Value::Push(values),
+        b'%' => Value::Map(pairs(values)),
+        // the attributes are ignored, the value follows them
+        _ => values.pop().unwrap_or(Value::Null),
+    }
+}
+
+
+fn pairs(values: Vec<Value>) -> Vec<(Value, Value)> {
+    let mut pairs = Vec::with_capacity(values.len() / 2);
+    let mut values = values.into_iter();
+    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+        pairs.push((key, value));
+    }
+    pairs
+}
+
+
+/// An aggregate received in part, with the elements decoded so far.
+#[derive(Debug)]
+struct Partial {
+    kind: u8,
+    remaining: i64,
+    values: Vec<Value>,
+}
+
+
+/// Decode the values received in many chunks.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    // the start of the next value or element in the buffer
+    pos: usize,
+    // the aggregates received in part, the innermost last
+    partials: Vec<Partial>,
+}
+
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
+
+    /// Append the data received, after dropping the values and the
+    /// elements decoded.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete value, `None` until it is received. The
+    /// elements of an aggregate are decoded as they are received.
+    pub fn decode(&mut self) -> io::Result<Option<Value>> {
+        'values: loop {
+            if self.partials.len() > MAX_DEPTH {
+                return Err(invalid("too deeply nested"));
+            }
+            let mut value = match parse(&self.buf, self.pos)? {
+                Some((item, next)) => {
+                    self.pos = next;
+                    match item {
+                        Item::Value(value) => value,
+                        Item::Aggregate(kind, 0) => aggregate(kind, Vec::new()),
+                        Item::Aggregate(kind, remaining) => {
+                            self.partials.push(Partial { kind, remaining, values: Vec::new() });
+                            continue;
+                        }
+                    }
+                }
+                None => return Ok(None),
+            };
+            // the value may be the last element of the aggregates
+            while let Some(mut partial) = self.partials.pop() {
+                partial.values.push(value);
+                partial.remaining -= 1;
+                if partial.remaining > 0 {
+                    self.partials.push(partial);
+                    continue 'values;
+                }
+                value = aggregate(partial.kind, partial.values);
+            }
+            return Ok(Some(value));
+        }
+    }
+}
+
+
+/// A message published on a channel the client subscribed to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// The channel of the message.
+    pub channel: Vec<u8>,
+    /// The pattern matching the channel, for a `PSUBSCRIBE`.
+    pub pattern: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+
+type ReplyCallback = Box<dyn FnOnce(Value)>;
+type MessageHandler = Box<dyn FnMut(Message)>;
+
+
+#[derive(Default)]
+struct Shared {
+    token: Option<Token>,
+    outgoing: Vec<u8>,
+    callbacks: VecDeque<ReplyCallback>,
+    on_message: Option<MessageHandler>,
+    // subscriptions confirmed by the server, and requested
+    subscriptions: i64,
+    subscribing: bool,
+}
+
+
+/// A handle to send commands on a connection to Redis.
+#[derive(Clone, Default)]
+pub struct RedisClient {
+    shared: Rc<RefCell<Shared>>,
+}
+
+
+impl RedisClient {
+    /// Connect to the Redis server on the loop.
+    pub fn connect(rio: &mut Rio, addr: &str) -> io::Result<RedisClient> {
+        let client = RedisClient::default();
+        let token = rio.connect(addr, Box::new(client.protocol()))?;
+        client.shared.borrow_mut().token = Some(token);
+        Ok(client)
+    }
+
+    /// The protocol of the connection, to connect it another way, such
+    /// as through a proxy.
+    pub fn protocol(&self) -> RedisProtocol {
+        RedisProtocol {
+            shared: self.shared.clone(),
+            decoder: Decoder::new(),
+        }
+    }
+
+    /// The token of the connection, once connected.
+    pub fn token(&self) -> Option<Token> {
+        self.shared.borrow().token
+    }
+
+    /// Queue the command, the callback is called with its reply, or with
+    /// an error if the connection is lost.
+    pub fn command<A, F>(&self, args: &[A], callback: F)
+        where A: AsRef<[u8]>,
+              F: FnOnce(Value) + 'static
+    {
+        let mut shared = self.shared.borrow_mut();
+        shared.outgoing.extend_from_slice(&encode_command(args));
+        shared.callbacks.push_back(Box::new(callback));
+    }
+
+    /// Set the handler of the messages of the subscribed channels.
+    pub fn on_message<F>(&self, handler: F)
+        where F: FnMut(Message) + 'static
+    {
+        self.shared.borrow_mut().on_message = Some(Box::new(handler));
+    }
+
+    /// Queue a `SUBSCRIBE` to the channels. Until the client unsubscribed
+    /// from all of them, only the subscription commands are accepted
+    /// by a RESP2 server.
+    pub fn subscribe<A: AsRef<[u8]>>(&self, channels: &[A]) {
+        self.subscription(b"SUBSCRIBE", channels);
+    }
+
+    /// Queue a `PSUBSCRIBE` to the channels matching the patterns.
+    pub fn psubscribe<A: AsRef<[u8]>>(&self, patterns: &[A]) {
+        self.subscription(b"PSUBSCRIBE", patterns);
+    }
+
+    /// Queue an `UNSUBSCRIBE` from the channels, all of them if empty.
+    pub fn unsubscribe<A: AsRef<[u8]>>(&self, channels: &[A]) {
+        self.subscription(b"UNSUBSCRIBE", channels);
+    }
+
+    /// Queue a `PUNSUBSCRIBE` from the patterns, all of them if empty.
+    pub fn punsubscribe<A: AsRef<[u8]>>(&self, patterns: &[A]) {
+        self.subscription(b"PUNSUBSCRIBE", patterns);
+    }
+
+    fn subscription<A: AsRef<[u8]>>(&self, command: &[u8], args: &[A]) {
+        let mut command = vec![command];
+        command.extend(args.iter().map(|arg| arg.as_ref()));
+        let mut shared = self.shared.borrow_mut();
+        shared.outgoing.extend_from_slice(&encode_command(&command));
+        shared.subscribing = true;
+    }
+
+    /// The number of commands waiting for their reply.
+    pub fn pending(&self) -> usize {
+        self.shared.borrow().callbacks.len()
+    }
+
+    /// Write the queued commands, from outside of a callback of the client.
+    pub fn flush(&self, rio: &mut Rio) -> io::Result<()> {
+        match self.token() {
+            Some(token) => rio.wake(token),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected")),
+        }
+    }
+}
+
+
+/// The protocol of a connection to Redis, driven by a `RedisClient`.
+pub struct RedisProtocol {
+    shared: Rc<RefCell<Shared>>,
+    decoder: Decoder,
+}
+
+
+const PUBSUB_KINDS: &[&[u8]] = &[b"message", b"pmessage", b"subscribe", b"psubscribe", b"unsubscribe", b"punsubscribe"];
+
+
+impl RedisProtocol {
+    fn write_outgoing(&self, transport: &mut Transport) {
+        let outgoing = self.shared.borrow_mut().outgoing.split_off(0);
+        if !outgoing.is_empty() {
+            transport.write_owned(outgoing);
+        }
+    }
+
+    /// The message of a push, or `None` for the confirmations of the
+    /// subscriptions. Return the push if it is not related to them.
+    fn read_push(&self, values: Vec<Value>) -> Result<Option<Message>, Vec<Value>> {
+        let kind = match values.first().and_then(|kind| kind.as_bytes()) {
+            Some(kind) if PUBSUB_KINDS.contains(&kind) => kind.to_vec(),
+            _ => return Err(values),
+        };
+        let mut values = values.into_iter().skip(1).map(|value| match value {
+            Value::BulkString(data) => data,
+            Value::SimpleString(data) => data.into_bytes(),
+            Value::Integer(count) => count.to_string().into_bytes(),
+            _ => Vec::new(),
+        });
+        match &kind[..] {
+            b"message" => {
+                Ok(Some(Message {
+                    channel: values.next().unwrap_or_default(),
+                    pattern: None,
+                    payload: values.next().unwrap_or_default(),
+                }))
+            }
+            b"pmessage" => {
+                let pattern = values.next();
+                Ok(Some(Message {
+                    channel: values.next().unwrap_or_default(),
+                    pattern,
+                    payload: values.next().unwrap_or_default(),
+                }))
+            }
+            _ => {
+                let count = values.nth(1)
+                    .and_then(|count| str::from_utf8(&count).ok().and_then(|count| i64::from_str(count).ok()))
+                    .unwrap_or(0);
+                let mut shared = self.shared.borrow_mut();
+                shared.subscriptions = count;
+                shared.subscribing = false;
+                Ok(None)
+            }
+        }
+    }
+
+    fn handle_value(&mut self, value: Value) {
+        let in_pubsub = {
+            let shared = self.shared.borrow();
+            shared.subscriptions > 0 || shared.subscribing
+        };
+        let value = match value {
+            Value::Push(values) => self.read_push(values).map_err(Value::Push),
+            Value::Array(values) if in_pubsub => self.read_push(values).map_err(Value::Array),
+            value => Err(value),
+        };
+        match value {
+            Ok(Some(message)) => {
+                let handler = self.shared.borrow_mut().on_message.take();
+                match handler {
+                    Some(mut handler) => {
+                        handler(message);
+                        let mut shared = self.shared.borrow_mut();
+                        if shared.on_message.is_none() {
+                            shared.on_message = Some(handler);
+                        }
+                    }
+                    None => debug!("No handler for the message of {:?}", message.channel),
+                }
+            }
+            Ok(None) => {}
+            Err(Value::Push(values)) => debug!("Ignoring the push {:?}", values),
+            Err(reply) => {
+                let callback = self.shared.borrow_mut().callbacks.pop_front();
+                match callback {
+                    Some(callback) => callback(reply),
+                    None => error!("Unexpected reply {:?}", reply),
+                }
+            }
+        }
+    }
+}
+
+
+impl Protocol for RedisProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        self.shared.borrow_mut().token = Some(transport.token());
+        self.write_outgoing(transport);
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.decoder.feed(data);
+        loop {
+            match self.decoder.decode() {
+                Ok(Some(value)) => self.handle_value(value),
+                Ok(None) => break,
+                Err(err) => {
+                    error!("{}, disconnecting", err);
+                    transport.hang_up();
+                    break;
+                }
+            }
+        }
+        self.write_outgoing(transport);
+    }
+
+    fn wakeup(&mut self, transport: &mut Transport) {
+        self.write_outgoing(transport);
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        let callbacks = {
+            let mut shared = self.shared.borrow_mut();
+            shared.token = None;
+            shared.subscriptions = 0;
+            shared.subscribing = false;
+            shared.callbacks.split_off(0)
+        };
+        for callback in callbacks {
+            callback(Value::Error(format!("Connection lost: {:?}", reason)));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use testing::ProtocolHarness;
+
+    use super::{encode_command, Decoder, Message, RedisClient, Value};
+
+    fn decode_all(data: &[u8]) -> Vec<Value> {
+        let mut decoder = Decoder::new();
+        let mut values = Vec::new();
+        // byte by byte, to check the incomplete values
+        for byte in data {
+            decoder.feed(&[*byte]);
+            while let Some(value) = decoder.decode().unwrap() {
+                values.push(value);
+            }
+        }
+        values
+    }
+
+    #[test]
+    pub fn test_resp2() {
+        let values = decode_all(b"+OK\r\n-ERR wrong\r\n:-42\r\n$5\r\nhel\r\n\r\n$-1\r\n*2\r\n$1\r\na\r\n*-1\r\n");
+        assert_eq!(values,
+                   vec![Value::SimpleString("OK".to_string()),
+                        Value::Error("ERR wrong".to_string()),
+                        Value::Integer(-42),
+                        Value::BulkString(b"hel\r\n".to_vec()),
+                        Value::Null,
+                        Value::Array(vec![Value::BulkString(b"a".to_vec()), Value::Null])]);
+    }
+
+    #[test]
+    pub fn test_resp3() {
+        let values = vec![Value::Null,
+                          Value::Double(1.5),
+                          Value::Double(f64::INFINITY),
+                          Value::Boolean(true),
+                          Value::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+                          Value::Verbatim("txt".to_string(), b"Some string".to_vec()),
+                          Value::Map(vec![(Value::SimpleString("first".to_string()), Value::Integer(1))]),
+                          Value::Set(vec![Value::Boolean(false)]),
+                          Value::Push(vec![Value::BulkString(b"message".to_vec())])];
+        let mut data = Vec::new();
+        for value in &values {
+            value.encode(&mut data);
+        }
+        assert_eq!(decode_all(&data), values);
+        assert_eq!(decode_all(b"!9\r\nERR wrong\r\n|1\r\n+ttl\r\n:3600\r\n:1\r\n"),
+                   vec![Value::Error("ERR wrong".to_string()), Value::Integer(1)]);
+    }
+
+    #[test]
+    pub fn test_partial() {
+        let mut decoder = Decoder::new();
+        decoder.feed(b"*3\r\n*2\r\n:1\r\n:2\r\n$5\r\nhel");
+        assert_eq!(decoder.decode().unwrap(), None);
+        // the elements received are decoded once, only the last is left
+        assert_eq!(&decoder.buf[decoder.pos..], b"$5\r\nhel");
+        decoder.feed(b"lo\r\n:3\r\n");
+        assert_eq!(decoder.decode().unwrap(),
+                   Some(Value::Array(vec![Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+                                          Value::BulkString(b"hello".to_vec()),
+                                          Value::Integer(3)])));
+        assert!(decoder.partials.is_empty());
+    }
+
+    #[test]
+    pub fn test_invalid() {
+        let mut decoder = Decoder::new();
+        decoder.feed(b"?\r\n");
+        assert!(decoder.decode().is_err());
+        let mut decoder = Decoder::new();
+        decoder.feed(b"$3\r\nabcd\r\n");
+        assert!(decoder.decode().is_err());
+        // hostile lengths and nesting
+        for data in &[&b"%4611686018427387904\r\n"[..], &b"$9223372036854775807\r\n"[..]] {
+            let mut decoder = Decoder::new();
+            decoder.feed(data);
+            assert_eq!(decoder.decode().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let mut decoder = Decoder::new();
+        decoder.feed(&b"*1\r\n".repeat(65));
+        assert!(decoder.decode().is_err());
+        let mut decoder = Decoder::new();
+        decoder.feed(&b"|0\r\n".repeat(100));
+        assert!(decoder.decode().is_err());
+        let mut decoder = Decoder::new();
+        decoder.feed(&b"*1\r\n".repeat(64));
+        decoder.feed(b":1\r\n");
+        assert!(decoder.decode().unwrap().is_some());
+    }
+
+    #[test]
+    pub fn test_pipelining() {
+        let client = RedisClient::default();
+        let replies = Rc::new(RefCell::new(Vec::new()));
+        for key in &["a", "b"] {
+            let replies = replies.clone();
+            client.command(&["GET", key], move |value| replies.borrow_mut().push(value));
+        }
+        let mut harness = ProtocolHarness::new(Box::new(client.protocol()));
+        harness.connect();
+        let mut expected = encode_command(&["GET", "a"]);
+        expected.extend_from_slice(&encode_command(&["GET", "b"]));
+        assert_eq!(harness.take_written(), expected);
+        harness.feed(b"$1\r\n1\r\n$-");
+        assert_eq!(client.pending(), 1);
+        harness.feed(b"1\r\n");
+        assert_eq!(*replies.borrow(), vec![Value::BulkString(b"1".to_vec()), Value::Null]);
+
+        // the commands queued by a callback are written after it
+        let follow_up = client.clone();
+        client.command(&["PING"], move |_| follow_up.command(&["QUIT"], |_| {}));
+        harness.wakeup();
+        assert_eq!(harness.take_written(), encode_command(&["PING"]));
+        harness.feed(b"+PONG\r\n");
+        assert_eq!(harness.take_written(), encode_command(&["QUIT"]));
+        harness.peer_hang_up();
+        assert_eq!(client.pending(), 0);
+    }
+
+    #[test]
+    pub fn test_pubsub() {
+        let client = RedisClient::default();
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let received = messages.clone();
+        client.on_message(move |message| received.borrow_mut().push(message));
+        client.subscribe(&["news"]);
+        let mut harness = ProtocolHarness::new(Box::new(client.protocol()));
+        harness.connect();
+        assert_eq!(harness.take_written(), encode_command(&["SUBSCRIBE", "news"]));
+        harness.feed(b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        harness.feed(b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n");
+        // RESP3
+        harness.feed(b">4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$3\r\nbye\r\n");
+        assert_eq!(*messages.borrow(),
+                   vec![Message {
+                            channel: b"news".to_vec(),
+                            pattern: None,
+                            payload: b"hello".to_vec(),
+                        },
+                        Message {
+                            channel: b"news".to_vec(),
+                            pattern: Some(b"n*".to_vec()),
+                            payload: b"bye".to_vec(),
+                        }]);
+
+        client.unsubscribe::<&str>(&[]);
+        harness.wakeup();
+        assert_eq!(harness.take_written(), encode_command(&["UNSUBSCRIBE"]));
+        harness.feed(b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n");
+        // back to the replies of the commands
+        let reply = Rc::new(RefCell::new(None));
+        let pong = reply.clone();
+        client.command(&["PING"], move |value| *pong.borrow_mut() = Some(value));
+        harness.wakeup();
+        harness.feed(b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+        assert_eq!(*reply.borrow(),
+                   Some(Value::Array(vec![Value::BulkString(b"pong".to_vec()), Value::BulkString(Vec::new())])));
+    }
+}
@@ -1,49 +1,130 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::io;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use std::io::{Read, Write};  // Used for TcpStream.read,  TcpStream.write
-use mio::{Poll, Token, Events, Event, Ready, PollOpt};
+use futures::{Async, Future};
+use futures::executor::{self, Notify, Spawn};
+use mio::{Poll, Token, Events, Event, Ready, PollOpt, Registration, SetReadiness};
+use mio::channel;
 use mio::tcp::{TcpListener, TcpStream};
+use mio::unix::EventedFd;
+use nix;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, FD_CLOEXEC, O_NONBLOCK};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SignalFd, SFD_CLOEXEC, SFD_NONBLOCK};
+use nix::libc::off_t;
+use nix::sys::sendfile::sendfile;
+use nix::sys::uio::{self, IoVec};
+use nix::unistd;
 
 use slab;
-use interface::{ServerFactory, Protocol, Reason};
-use transport::Transport;
+use activation::HANDOVER_FDS;
+use listener::{Listener, ListenerBuilder};
+use hub::Hub;
+use pool::{Job, ThreadPool};
+use clock::{Clock, SystemClock};
+use options::SocketOptions;
+use proxy_header::{ProxyHeader, ProxyVersion};
+use interface::{self, ServerFactory, OverloadPolicy, Protocol, Reason, SubprocessProtocol, Tunnel};
+use transport::{Action, Transport, SubprocessTransport};
 
 const CONNS_MAX: usize = 65_536;
 const BUF_SIZE: usize = 4096;
+const EVENTS_CAPACITY: usize = 1024;
+const POLL_TIMEOUT_MS: u64 = 500;
+const EXECUTOR_THREADS: usize = 4;
+const IOV_MAX: usize = 64;
 
 type Slab<T> = slab::Slab<T, Token>;
 
 
+fn unspecified_addr() -> SocketAddr {
+    FromStr::from_str("0.0.0.0:0").unwrap()
+}
+
+
+/// Parse an IP address and a port, such as `127.0.0.1:8080`.
+pub(crate) fn parse_addr(addr: &str) -> io::Result<SocketAddr> {
+    FromStr::from_str(addr).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid address {}", addr)))
+}
+
+
 #[derive(Clone)]
 enum ConnectionType {
     Server,
     Client,
+    Process,
+    Pipe,
+    Signal,
+    Task,
+    Executor,
+    Stop,
 }
 
 
 struct ServerConnection {
-    server: Box<ServerFactory>,
-    socket: TcpListener,
+    server: Box<dyn ServerFactory>,
+    socket: Option<TcpListener>,
+    connections: usize,
+    paused: bool,
+    on_drained: Option<DrainCallback>,
+    proxy_protocol: bool,
+}
+
+impl ServerConnection {
+    /// The listening socket, until the listener is closed.
+    fn socket(&self) -> io::Result<&TcpListener> {
+        self.socket.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Listener closed"))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.socket.is_none()
+    }
 }
 
 
 struct ClientConnection {
-    protocol: Box<Protocol>,
+    protocol: Box<dyn Protocol>,
     socket: TcpStream,
+    listener: Option<Token>,
     interest: Ready,
     transport: Transport,
+    eof: bool,
+    keep_open: bool,
+    write_shut: bool,
+    // the start of the PROXY protocol header, until it is read
+    proxy_header: Option<Vec<u8>>,
+    // an outgoing connection, until the connect completes
+    connecting: bool,
 }
 
 impl ClientConnection {
-    fn new(protocol: Box<Protocol>, socket: TcpStream) -> ClientConnection {
+    fn new(protocol: Box<dyn Protocol>, socket: TcpStream, listener: Option<Token>) -> ClientConnection {
+        let transport = Transport::with_socket(socket.as_raw_fd());
         ClientConnection {
-            protocol: protocol,
-            socket: socket,
+            protocol,
+            socket,
+            listener,
             interest: Ready::hup() | Ready::readable(),
-            transport: Transport::new(),
+            transport,
+            eof: false,
+            keep_open: false,
+            write_shut: false,
+            proxy_header: None,
+            connecting: false,
         }
     }
 
@@ -55,347 +136,2023 @@ impl ClientConnection {
         self.interest == Ready::none()
     }
 
-    fn handle_hup(&mut self) {
-        self.interest = Ready::none();
+    /// False until the PROXY protocol header is read, if the listener
+    /// expects one, or until the connect completes.
+    fn is_made(&self) -> bool {
+        self.proxy_header.is_none() && !self.connecting
+    }
+
+    /// The peer shut down its writing side, the connection is closed
+    /// once the pending data has been written, unless the protocol
+    /// keeps it open.
+    fn handle_eof(&mut self) {
+        if !self.is_made() {
+            info!("Connection closed before the PROXY protocol header");
+            self.interest = Ready::none();
+            return;
+        }
+        debug!("End of file received on {:?}", self.transport.token());
+        self.eof = true;
+        self.keep_open = self.protocol.eof_received(&mut self.transport);
     }
 
-    fn handle_read(&mut self) -> io::Result<()> {
-        let mut read_bytes: Vec<u8> = Vec::new();
+    /// Read everything available, directly in the buffer of the protocol
+    /// if it provides one, or in the buffer of the loop.
+    /// Return true if the peer shut down its writing side.
+    fn handle_read(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        if !self.is_made() && !self.read_proxy_header(buf)? {
+            return Ok(false);
+        }
         loop {
-            let (buf, read_len) = {
-                let mut buf = [0; BUF_SIZE];
-                let read_len = try!(self.socket.read(&mut buf[..]));
-                // let s_data = str::from_utf8(&buf).unwrap();
-                // info!("<<< {}", s_data);
-                (buf, read_len)
+            let (result, requested_len, buffered) = match self.protocol.get_buffer(buf.len()) {
+                Some(ref mut protocol_buf) if !protocol_buf.is_empty() => {
+                    (self.socket.read(protocol_buf), protocol_buf.len(), true)
+                }
+                _ => (self.socket.read(buf), buf.len(), false),
             };
-            if read_len > 0 {
-                read_bytes.extend(buf[0..read_len].iter());
-                if read_len < BUF_SIZE {
+            let read_len = match result {
+                Ok(read_len) => read_len,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
                     debug!("Nothing more to read");
                     break;
-                } else {
-                    debug!("More data to read");
                 }
-            } else {
+                Err(err) => return Err(err),
+            };
+            // let s_data = str::from_utf8(&buf).unwrap();
+            // info!("<<< {}", s_data);
+            if read_len == 0 {
                 debug!("Nothing to read");
+                return Ok(true);
+            }
+            if buffered {
+                self.protocol.buffer_updated(read_len, &mut self.transport);
+            } else {
+                self.protocol.data_received(&buf[..read_len], &mut self.transport);
+            }
+            if self.transport.reading_paused() {
+                debug!("Reading paused");
                 break;
             }
+            if read_len < requested_len {
+                debug!("Nothing more to read");
+                break;
+            }
+            debug!("More data to read");
         }
-        if read_bytes.len() > 0 {
-            self.protocol.data_received(&read_bytes[..], &mut self.transport);
-        }
-        Ok(())
+        Ok(false)
     }
 
-    fn handle_write(&mut self) {
-
-        {
-            // a block where transport muting
-
-            debug!("handle write");
-            let buf = &mut self.transport.buf();
-            let to_write_len = buf.len();
-            if to_write_len > 0 {
-                // let s_data = str::from_utf8(&buf[..]).unwrap();
-                // info!(">>> {}", s_data);
-
-                let result = self.socket.write(&buf[..]);
-                match result {
-                    Ok(written_len) => {
-                        debug!("Write {} bytes", written_len);
-                        if to_write_len != written_len {
-                            error!("{} bytes to write but {} written, hanging up",
-                                   to_write_len,
-                                   written_len);
-                            self.interest = Ready::hup();
-                        }
-                    }
-                    Err(err) => {
-                        error!("Error {} while writing to the socket, disconnecting", err);
-                        self.interest = Ready::hup();
-                    }
-                };
+    /// Read the PROXY protocol header, then make the connection with
+    /// the source address it carries, and pass it the data read after
+    /// the header. Return false until the header is read.
+    fn read_proxy_header(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut header = self.proxy_header.take().unwrap_or_default();
+        loop {
+            let read_len = match self.socket.read(buf) {
+                Ok(0) => {
+                    info!("Connection closed before the PROXY protocol header");
+                    self.interest = Ready::none();
+                    return Ok(false);
+                }
+                Ok(read_len) => read_len,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.proxy_header = Some(header);
+                    return Ok(false);
+                }
+                Err(err) => {
+                    self.proxy_header = Some(header);
+                    return Err(err);
+                }
+            };
+            header.extend_from_slice(&buf[..read_len]);
+            match ProxyHeader::parse(&header[..]) {
+                Ok(Some((parsed, len))) => {
+                    debug!("PROXY protocol header read: {:?}", parsed);
+                    let data = header.split_off(len);
+                    self.transport.set_proxied_addr(parsed.source());
+                    self.protocol.connection_made(&mut self.transport);
+                    interface::deliver(&mut *self.protocol, &data[..], &mut self.transport);
+                    return Ok(true);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("{}, disconnecting", err);
+                    self.proxy_header = Some(header);
+                    self.interest = Ready::none();
+                    return Ok(false);
+                }
             }
-        };
+        }
+    }
 
-        self.transport.clear();
+    /// Write the pending buffers with `writev` and the files with
+    /// `sendfile`, what cannot be written is kept until the socket is
+    /// writable again. The buffer is used if `sendfile` is not supported.
+    fn handle_write(&mut self, buf: &mut [u8]) {
+        debug!("handle write");
+        while self.transport.should_write() {
+            let result = match self.transport.file() {
+                Some((file, offset, len)) => send_file(self.socket.as_raw_fd(), file, offset, len, buf),
+                None => {
+                    let slices = self.transport.slices(IOV_MAX);
+                    let iovecs = slices.iter().map(|slice| IoVec::from_slice(slice)).collect::<Vec<_>>();
+                    uio::writev(self.socket.as_raw_fd(), &iovecs[..])
+                }
+            };
+            match result {
+                Ok(0) => {
+                    error!("Nothing written, the file to send may be truncated, disconnecting");
+                    self.transport.clear();
+                    self.interest = Ready::hup();
+                }
+                Ok(written_len) => {
+                    debug!("Write {} bytes", written_len);
+                    self.transport.consume(written_len);
+                }
+                Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                    debug!("{} bytes left to write, waiting for the socket", self.transport.pending());
+                    break;
+                }
+                Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(err) => {
+                    error!("Error {} while writing to the socket, disconnecting", err);
+                    self.transport.clear();
+                    self.interest = Ready::hup();
+                }
+            }
+        }
 
+        if self.transport.should_write() || self.is_finished() {
+            return;
+        }
         if self.transport.hup() {
             info!("Peer is disconnecting, will unregister connection");
             self.interest = Ready::none();
+        } else if self.eof && !self.keep_open {
+            info!("Peer disconnected, will unregister connection");
+            self.interest = Ready::none();
+        } else if self.transport.eof() && !self.write_shut {
+            debug!("Shutting down the writing side of {:?}", self.transport.token());
+            if let Err(err) = self.socket.shutdown(net::Shutdown::Write) {
+                error!("Cannot shut down the socket: {}", err);
+            }
+            self.write_shut = true;
+        }
+        if self.eof && self.write_shut {
+            info!("Both sides shut down, will unregister connection");
+            self.interest = Ready::none();
         }
     }
-}
 
+    /// The reason of the close, once finished.
+    fn reason(&self) -> Reason {
+        if self.transport.hup() || (self.eof && self.write_shut) {
+            Reason::HangUp
+        } else {
+            Reason::ConnectionLost
+        }
+    }
 
-struct Connection {
-    connection_type: ConnectionType,
-    server: Option<ServerConnection>,
-    client: Option<ClientConnection>,
-    peer_addr: SocketAddr,
+    /// The events to poll, writable while connecting or while there are
+    /// pending buffers, only the errors once the reading side is closed
+    /// or paused.
+    fn poll_interest(&self) -> Ready {
+        if self.is_finished() {
+            return Ready::none();
+        }
+        let interest = if self.eof || self.transport.reading_paused() {
+            Ready::error()
+        } else {
+            self.interest
+        };
+        if self.connecting || self.transport.should_write() {
+            interest | Ready::writable()
+        } else {
+            interest
+        }
+    }
 }
 
 
-impl Connection {
-    fn new_server(server: Box<ServerFactory>,
-                  peer_addr: SocketAddr,
-                  socket: TcpListener)
-                  -> Connection {
-        Connection {
-            connection_type: ConnectionType::Server,
-            server: Some(ServerConnection {
-                server: server,
-                socket: socket,
-            }),
-            client: None,
-            peer_addr: peer_addr,
+/// Standard input, output and error of a spawned process.
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+const STDERR: RawFd = 2;
+
+
+/// Send a region of the file to the socket with `sendfile`, or copy it
+/// with the buffer if the file does not support it.
+fn send_file(socket: RawFd, file: &File, offset: u64, len: usize, buf: &mut [u8]) -> nix::Result<usize> {
+    let mut sendfile_offset = offset as off_t;
+    match sendfile(socket, file.as_raw_fd(), Some(&mut sendfile_offset), len) {
+        Err(nix::Error::Sys(Errno::EINVAL)) | Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            debug!("sendfile not supported, copying the file");
+            let len = cmp::min(len, buf.len());
+            let read_len = file.read_at(&mut buf[..len], offset)
+                .map_err(|err| nix::Error::Sys(Errno::from_i32(err.raw_os_error().unwrap_or(0))))?;
+            unistd::write(socket, &buf[..read_len])
         }
+        result => result,
     }
+}
 
-    fn new_client(protocol: Box<Protocol>, peer_addr: SocketAddr, socket: TcpStream) -> Connection {
-        Connection {
-            connection_type: ConnectionType::Client,
-            client: Some(ClientConnection::new(protocol, socket)),
-            server: None,
-            peer_addr: peer_addr,
+
+/// Write the message to a connection that will not be served, and close it.
+fn reject(mut sock: TcpStream, message: &[u8]) {
+    if !message.is_empty() {
+        if let Err(err) = sock.write(message) {
+            debug!("Cannot write the rejection message: {}", err);
         }
     }
+    let _ = sock.shutdown(net::Shutdown::Both);
+}
 
-    fn server_ref(&self) -> &ServerConnection {
-        self.server.as_ref().unwrap()
-    }
 
-    fn client_ref(&self) -> &ClientConnection {
-        self.client.as_ref().unwrap()
-    }
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    fcntl(fd, FcntlArg::F_SETFL(O_NONBLOCK))?;
+    Ok(())
+}
 
-    fn client_mut(&mut self) -> &mut ClientConnection {
-        self.client.as_mut().unwrap()
-    }
 
-    fn alive(&self) -> bool {
-        return match self.connection_type {
-            ConnectionType::Server => true,
-            ConnectionType::Client => {
-                match self.client_ref().peer_addr() {
-                    Ok(_) => true,
-                    Err(_) => false,
-                }
-            }
-        };
-    }
+struct PipeConnection {
+    process: Token,
+    fd: RawFd,
 }
 
 
-/// The I/O Loop
-pub struct Rio {
-    poll: Poll,
-    connections: Slab<Connection>,
-    running: bool
+struct ProcessConnection {
+    protocol: Box<dyn SubprocessProtocol>,
+    child: Child,
+    transport: SubprocessTransport,
+    pipes: Vec<Token>,
 }
 
+impl ProcessConnection {
+    fn new(protocol: Box<dyn SubprocessProtocol>, child: Child) -> ProcessConnection {
+        let transport = SubprocessTransport::new(child.id());
+        ProcessConnection {
+            protocol,
+            child,
+            transport,
+            pipes: Vec::new(),
+        }
+    }
 
-impl Rio {
-    /// Instanciate the IOLoop, should be called once.
-    pub fn new() -> Rio {
-        let poll: Poll = Poll::new().unwrap();
-        let connections = Slab::with_capacity(CONNS_MAX);
-        Rio {
-            running: false,
-            poll: poll,
-            connections: connections,
+    fn raw_fd(&self, fd: RawFd) -> Option<RawFd> {
+        match fd {
+            STDIN => self.child.stdin.as_ref().map(|pipe| pipe.as_raw_fd()),
+            STDOUT => self.child.stdout.as_ref().map(|pipe| pipe.as_raw_fd()),
+            STDERR => self.child.stderr.as_ref().map(|pipe| pipe.as_raw_fd()),
+            _ => None,
         }
     }
 
-    /// Will listen on the given address when the loop will start.
-    /// The ServerFactory.build_protocol method will be called on every
-    /// new client connection.
-    pub fn listen(&mut self, addr: &str, server: Box<ServerFactory>) -> Result<Token, io::Error> {
-        info!("Rio is listenning on {}", addr);
-        let sock_addr: SocketAddr = FromStr::from_str(addr).unwrap();
-        debug!("Bind the server socket {}", addr);
-        let sock = TcpListener::bind(&sock_addr).unwrap();
-        let result = self.connections.insert(Connection::new_server(server, sock_addr, sock));
-        match result {
-            Ok(token) => {
-                let _ = self.poll.register(&self.connections[token].server_ref().socket,
-                                           token,
-                                           Ready::readable() | Ready::writable(),
-                                           PollOpt::edge());
-                return Ok(token);
-            }
-            Err(_) => {
-                error!("Cannot register server {:?}", addr);
-                return Err(io::Error::new(io::ErrorKind::Other, format!("Cannot register server {:?}", addr)));
-            }
+    fn close_pipe(&mut self, fd: RawFd) {
+        debug!("Closing pipe {} of process {}", fd, self.child.id());
+        match fd {
+            STDIN => self.child.stdin = None,
+            STDOUT => self.child.stdout = None,
+            _ => self.child.stderr = None,
         }
     }
 
-    /// Will listen on the given address when the loop will start.
-    /// The ServerFactory.build_protocol method will be called on every
-    /// new client connection.
-    pub fn connect(&mut self, addr: &str, client: Box<Protocol>) -> Result<Token, io::Error> {
-        info!("Connecting to socket {}", addr);
-        let sock_addr: SocketAddr = FromStr::from_str(addr).unwrap();
+    /// Read everything available on the pipe, return true if the pipe
+    /// reach the end of file.
+    fn handle_read(&mut self, fd: RawFd, buf: &mut [u8]) -> bool {
+        let mut eof = false;
+        loop {
+            let result = match fd {
+                STDOUT => self.child.stdout.as_mut().map(|pipe| pipe.read(buf)),
+                _ => self.child.stderr.as_mut().map(|pipe| pipe.read(buf)),
+            };
+            match result {
+                Some(Ok(0)) | None => {
+                    debug!("End of file on pipe {}", fd);
+                    eof = true;
+                    break;
+                }
+                Some(Ok(read_len)) => {
+                    self.protocol.pipe_data_received(fd, &buf[0..read_len], &mut self.transport)
+                }
+                Some(Err(ref err)) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Some(Err(ref err)) if err.kind() == io::ErrorKind::Interrupted => {}
+                Some(Err(err)) => {
+                    error!("Error {} while reading pipe {}, closing it", err, fd);
+                    eof = true;
+                    break;
+                }
+            }
+        }
+        eof
+    }
 
-        let sock = TcpStream::connect(&sock_addr).unwrap();
-        let result = self.connections.insert(Connection::new_client(client, sock_addr, sock));
-        match result {
-            Ok(token) => {
-                let client = self.connections[token].client_mut();
-                client.protocol.connection_made(&mut client.transport);
-                let _ = self.poll.register(&client.socket,
-                                           token,
-                                           Ready::all(),
-                                           PollOpt::all());
-                debug!(" socket {} registered in the poller", addr);
-                return Ok(token);
+    /// Write the pending data to the standard input, return true if the
+    /// pipe has to be closed.
+    fn handle_write(&mut self) -> bool {
+        loop {
+            if self.transport.buf().is_empty() {
+                break;
             }
-            Err(_) => {
-                error!("Cannot register client {:?}", addr);
-                return Err(io::Error::new(io::ErrorKind::Other, format!("Cannot register client {:?}", addr)));
+            let result = match self.child.stdin.as_mut() {
+                Some(pipe) => pipe.write(&self.transport.buf()[..]),
+                None => return false,
+            };
+            match result {
+                Ok(written_len) => {
+                    debug!("Write {} bytes to stdin", written_len);
+                    self.transport.consume(written_len);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    error!("Error {} while writing to stdin, closing it", err);
+                    return true;
+                }
             }
         }
+        self.transport.should_close_stdin()
     }
+}
 
-    /// Start the io loop
-    pub fn run_forever(&mut self) {
-        self.run_until(&|_: &Rio| -> bool { true });
-    }
 
-    pub fn run_until(&mut self, is_done: &Fn(&Rio) -> bool) {
+type SignalHandler = Box<dyn FnMut(&mut Rio)>;
 
-        info!("Start polling");
+type TimerCallback = Box<dyn FnOnce(&mut Rio)>;
 
-        let timeout = Duration::from_millis(500);
-        let mut events = Events::with_capacity(1024);
+type DrainCallback = Box<dyn FnOnce(&mut Rio)>;
 
-        self.running = true;
-        while self.running {
-            // debug!("Polling...");
-            self.poll.poll(&mut events, Some(timeout)).unwrap();
-
-            for event in events.iter() {
-                let token = event.token();
-                debug!("Got event for {:?}", token);
-                let _ = match self.connections[token].connection_type {
-                    ConnectionType::Server => self.handle_server(token),
-                    ConnectionType::Client => self.handle_client(token, event),
-                };
-            }
-            self.running = is_done(self)
-        }
-    }
 
+struct SignalConnection {
+    fd: SignalFd,
+    // the signals read, and the ones with a handler
+    mask: SigSet,
+    handled: SigSet,
+    // SIGCHLD is read to reap the spawned processes
+    children: bool,
+}
 
-    pub fn contains(&self, token: Token) -> bool {
-        return self.connections.contains(token)
+impl SignalConnection {
+    fn new() -> io::Result<SignalConnection> {
+        let mask = SigSet::empty();
+        let fd = SignalFd::with_flags(&mask, SFD_NONBLOCK | SFD_CLOEXEC)?;
+        Ok(SignalConnection { fd, mask, handled: SigSet::empty(), children: false })
     }
 
-    fn handle_server(&mut self, token: Token) -> io::Result<()> {
-        while self.running {
-            let (sock, addr) = try!(self.connections[token].server_ref().socket.accept());
-
-            info!("Accepting connection from {:?}", addr);
-
-            debug!("Building procotol");
-            let protocol = {
-                let server = self.connections[token].server.as_ref();
-                server.unwrap().server.build_protocol()
-            };
+    fn add(&mut self, signal: Signal) -> io::Result<()> {
+        self.handled.add(signal);
+        self.block(signal)
+    }
 
-            debug!("Take a token for the connection");
-            let result = self.connections.insert(Connection::new_client(protocol, addr, sock));
-            match result {
-                Ok(client_token) => {
-                    debug!("Registering procotol");
-                    let mut client = self.connections[client_token].client_mut();
-                    client.protocol.connection_made(&mut client.transport);
-                    try!(self.poll.register(&client.socket,
-                                            client_token,
-                                            Ready::readable() | Ready::writable(),
-                                            PollOpt::edge() | PollOpt::oneshot()));
-                }
-                Err(_) => error!("Cannot register client"),
+    /// Read SIGCHLD, the loop wakes up when a process exits.
+    fn watch_children(&mut self) -> io::Result<()> {
+        self.children = true;
+        self.block(Signal::SIGCHLD)
+    }
 
-            }
+    fn block(&mut self, signal: Signal) -> io::Result<()> {
+        if self.mask.contains(signal) {
+            return Ok(());
         }
+        let mut signals = SigSet::empty();
+        signals.add(signal);
+        signals.thread_block()?;
+        self.mask.add(signal);
+        self.fd.set_mask(&self.mask)?;
         Ok(())
     }
 
-    fn handle_client(&mut self, token: Token, event: Event) -> io::Result<()> {
-        debug!("handle client, {:?}", event);
-
-        if !&self.connections.contains(token) {
-            error!("Ignoring unkown token to handle");
+    fn remove(&mut self, signal: Signal) -> io::Result<()> {
+        self.handled.remove(signal);
+        if signal == Signal::SIGCHLD && self.children {
             return Ok(());
         }
+        self.mask.remove(signal);
+        self.fd.set_mask(&self.mask)?;
+        let mut signals = SigSet::empty();
+        signals.add(signal);
+        signals.thread_unblock()?;
+        Ok(())
+    }
 
-        let kind = event.kind();
-
-        if !&self.connections[token].alive() || kind.is_error() {
-            error!("Connection failed {:?}", &self.connections[token].peer_addr);
-            info!("Removing connection {:?}",
-                  &self.connections[token].peer_addr);
-
-            {
-                let client = &mut self.connections[token].client_mut();
-                client.protocol.connection_lost(Reason::ConnectionError);
+    fn read_signals(&mut self) -> Vec<Signal> {
+        let mut signals = Vec::new();
+        loop {
+            match self.fd.read_signal() {
+                Ok(Some(info)) => {
+                    match Signal::from_c_int(info.ssi_signo as i32) {
+                        Ok(signal) => signals.push(signal),
+                        Err(_) => error!("Unknown signal {} received", info.ssi_signo),
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Error {} while reading signals", err);
+                    break;
+                }
             }
-            self.connections.remove(token);
-            return Ok(());
         }
+        signals
+    }
+}
 
-        let mut finished = false;
-        let client_addr = &self.connections[token].client_ref().peer_addr().unwrap().clone();
-        {
-            let mut client = &mut self.connections[token].client_mut();
 
-            debug!("handle client {:?} {:?}", token, client_addr);
+type BoxFuture = Box<dyn Future<Item = (), Error = ()>>;
 
-            if kind.is_hup() {
-                debug!("handle hup {:?} {:?}", token, client_addr);
-                client.handle_hup();
-                finished = true;
-            } else {
-                if kind.is_readable() {
-                    debug!("handle readable {:?} {:?}", token, client_addr);
-                    try!(client.handle_read());
-                }
 
-                if kind.is_writable() || client.transport.should_write() {
-                    debug!("handle writable {:?} {:?}", token, client_addr);
-                    client.handle_write();
-                }
-            }
+/// Notify the loop that the task can make progress, from any thread.
+struct TaskNotify {
+    readiness: SetReadiness,
+}
 
-            if client.is_finished() {
-                info!("Closing connection with {:?} {:?}", token, client_addr);
-                match client.peer_addr() {
-                    Ok(addr) => info!("Connection closed {:?}", addr),
-                    Err(_) => error!("Connection closed (Peer already disconnected)"),
-                }
-                if client.transport.hup() {
-                    info!("Peer hang up {:?} {:?}", token, client_addr);
-                    client.protocol.connection_lost(Reason::HangUp);
-                } else {
-                    info!("Connection lost {:?} {:?}", token, client_addr);
-                    client.protocol.connection_lost(Reason::ConnectionLost);
-                }
-                try!(self.poll.deregister(&client.socket));
-                finished = true;
-            } else {
-                info!("Reregister token {:?} {:?}", token, client_addr);
-                try!(self.poll.reregister(&client.socket, token, client.interest, PollOpt::edge()));
+impl Notify for TaskNotify {
+    fn notify(&self, _id: usize) {
+        if let Err(err) = self.readiness.set_readiness(Ready::readable()) {
+            error!("Cannot notify the task: {}", err);
+        }
+    }
+}
+
+
+struct TaskConnection {
+    task: Spawn<BoxFuture>,
+    // the task is polled on the events of the registration
+    _registration: Registration,
+    notify: Arc<TaskNotify>,
+}
+
+impl TaskConnection {
+    /// Poll the future, true once it is completed.
+    fn poll(&mut self, token: Token) -> io::Result<bool> {
+        self.notify.readiness.set_readiness(Ready::none())?;
+        match self.task.poll_future_notify(&self.notify, token.0) {
+            Ok(Async::NotReady) => Ok(false),
+            Ok(Async::Ready(())) => Ok(true),
+            Err(()) => {
+                error!("Task {:?} failed", token);
+                Ok(true)
             }
         }
+    }
+}
 
 
-        if finished {
-            info!("Removing connection {:?} {:?}", token, client_addr);
-            self.connections.remove(token);
+struct Connection {
+    connection_type: ConnectionType,
+    server: Option<ServerConnection>,
+    client: Option<ClientConnection>,
+    process: Option<ProcessConnection>,
+    pipe: Option<PipeConnection>,
+    signal: Option<SignalConnection>,
+    task: Option<TaskConnection>,
+    executor: Option<ThreadPool>,
+    stop: Option<channel::Receiver<()>>,
+    peer_addr: SocketAddr,
+}
+
+
+impl Connection {
+    fn new_server(server: Box<dyn ServerFactory>,
+                  peer_addr: SocketAddr,
+                  socket: TcpListener,
+                  proxy_protocol: bool)
+                  -> Connection {
+        Connection {
+            connection_type: ConnectionType::Server,
+            server: Some(ServerConnection {
+                server,
+                socket: Some(socket),
+                connections: 0,
+                paused: false,
+                on_drained: None,
+                proxy_protocol,
+            }),
+            client: None,
+            process: None,
+            pipe: None,
+            signal: None,
+            task: None,
+            executor: None,
+            stop: None,
+            peer_addr,
         }
+    }
+
+    fn new_client(protocol: Box<dyn Protocol>,
+                  peer_addr: SocketAddr,
+                  socket: TcpStream,
+                  listener: Option<Token>)
+                  -> Connection {
+        Connection {
+            connection_type: ConnectionType::Client,
+            client: Some(ClientConnection::new(protocol, socket, listener)),
+            server: None,
+            process: None,
+            pipe: None,
+            signal: None,
+            task: None,
+            executor: None,
+            stop: None,
+            peer_addr,
+        }
+    }
+
+    fn new_process(protocol: Box<dyn SubprocessProtocol>, child: Child) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Process,
+            server: None,
+            client: None,
+            process: Some(ProcessConnection::new(protocol, child)),
+            pipe: None,
+            signal: None,
+            task: None,
+            executor: None,
+            stop: None,
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn new_pipe(process: Token, fd: RawFd) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Pipe,
+            server: None,
+            client: None,
+            process: None,
+            pipe: Some(PipeConnection { process, fd }),
+            signal: None,
+            task: None,
+            executor: None,
+            stop: None,
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn new_signal(signal: SignalConnection) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Signal,
+            server: None,
+            client: None,
+            process: None,
+            pipe: None,
+            signal: Some(signal),
+            task: None,
+            executor: None,
+            stop: None,
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn new_task(task: TaskConnection) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Task,
+            server: None,
+            client: None,
+            process: None,
+            pipe: None,
+            signal: None,
+            task: Some(task),
+            executor: None,
+            stop: None,
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn new_executor(pool: ThreadPool) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Executor,
+            server: None,
+            client: None,
+            process: None,
+            pipe: None,
+            signal: None,
+            task: None,
+            executor: Some(pool),
+            stop: None,
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn new_stop(receiver: channel::Receiver<()>) -> Connection {
+        Connection {
+            connection_type: ConnectionType::Stop,
+            server: None,
+            client: None,
+            process: None,
+            pipe: None,
+            signal: None,
+            task: None,
+            executor: None,
+            stop: Some(receiver),
+            peer_addr: unspecified_addr(),
+        }
+    }
+
+    fn server_ref(&self) -> &ServerConnection {
+        self.server.as_ref().unwrap()
+    }
+
+    fn server_mut(&mut self) -> &mut ServerConnection {
+        self.server.as_mut().unwrap()
+    }
+
+    fn client_ref(&self) -> &ClientConnection {
+        self.client.as_ref().unwrap()
+    }
+
+    fn client_mut(&mut self) -> &mut ClientConnection {
+        self.client.as_mut().unwrap()
+    }
+
+    fn process_ref(&self) -> &ProcessConnection {
+        self.process.as_ref().unwrap()
+    }
+
+    fn process_mut(&mut self) -> &mut ProcessConnection {
+        self.process.as_mut().unwrap()
+    }
+
+    fn pipe_ref(&self) -> &PipeConnection {
+        self.pipe.as_ref().unwrap()
+    }
+
+    fn signal_mut(&mut self) -> &mut SignalConnection {
+        self.signal.as_mut().unwrap()
+    }
+
+    fn task_mut(&mut self) -> &mut TaskConnection {
+        self.task.as_mut().unwrap()
+    }
+
+    fn executor_mut(&mut self) -> &mut ThreadPool {
+        self.executor.as_mut().unwrap()
+    }
+
+    fn stop_ref(&self) -> &channel::Receiver<()> {
+        self.stop.as_ref().unwrap()
+    }
+
+    fn alive(&self) -> bool {
+        match self.connection_type {
+            ConnectionType::Client => self.client_ref().peer_addr().is_ok(),
+            _ => true,
+        }
+    }
+}
+
+
+/// Stop the loop from any thread, see `Rio::stopper`.
+#[derive(Clone)]
+pub struct Stopper {
+    sender: channel::Sender<()>,
+}
+
+impl Stopper {
+    /// Stop the loop after its current iteration, even if it is
+    /// waiting for events. Fail if the loop is dropped.
+    pub fn stop(&self) -> io::Result<()> {
+        self.sender.send(()).map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "The loop is dropped"))
+    }
+}
+
+
+/// A timer scheduled by `Rio::call_later`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timer {
+    deadline: Instant,
+    id: usize,
+}
+
+impl Timer {
+    /// The time the timer is due, according to the loop clock.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+
+/// Wake the connections from outside of the loop callbacks, such as
+/// from the futures spawned on the loop, see `Rio::wake`.
+#[derive(Clone, Debug)]
+pub struct Waker {
+    tokens: Rc<RefCell<Vec<Token>>>,
+}
+
+impl Waker {
+    /// The protocol of the connection is woken up once the
+    /// current iteration of the loop is done.
+    pub fn wake(&self, token: Token) {
+        let mut tokens = self.tokens.borrow_mut();
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+}
+
+
+/// Tune the allocations and the latency of the loop.
+pub struct RioBuilder {
+    connections_capacity: usize,
+    max_connections: Option<usize>,
+    events_capacity: usize,
+    poll_timeout: Duration,
+    read_buffer_size: usize,
+    executor_threads: usize,
+    clock: Box<dyn Clock>,
+}
+
+
+impl RioBuilder {
+    /// The defaults of `Rio::new`, room for 65536 connections,
+    /// 1024 events per poll, a 500 ms poll timeout, a 4 KiB read buffer
+    /// and 4 executor threads.
+    pub fn new() -> RioBuilder {
+        RioBuilder {
+            connections_capacity: CONNS_MAX,
+            max_connections: None,
+            events_capacity: EVENTS_CAPACITY,
+            poll_timeout: Duration::from_millis(POLL_TIMEOUT_MS),
+            read_buffer_size: BUF_SIZE,
+            executor_threads: EXECUTOR_THREADS,
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// The number of servers, connections, processes and pipes
+    /// registered at the same time, allocated on build.
+    pub fn connections_capacity(mut self, capacity: usize) -> RioBuilder {
+        self.connections_capacity = capacity;
+        self
+    }
+
+    /// The maximum number of connections open at the same time,
+    /// see `Rio::set_max_connections`, the capacity by default.
+    pub fn max_connections(mut self, max_connections: usize) -> RioBuilder {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// The maximum number of events handled per iteration of the loop.
+    pub fn events_capacity(mut self, capacity: usize) -> RioBuilder {
+        self.events_capacity = capacity;
+        self
+    }
+
+    /// The maximum duration of a poll without events, the processes
+    /// exit and the stop condition of `Rio::run_until` are checked
+    /// after every poll.
+    pub fn poll_timeout(mut self, timeout: Duration) -> RioBuilder {
+        self.poll_timeout = timeout;
+        self
+    }
+
+    /// The size of the buffer the sockets and pipes are read in.
+    pub fn read_buffer_size(mut self, size: usize) -> RioBuilder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// The number of threads running the work of `Rio::run_in_executor`,
+    /// started on its first call.
+    pub fn executor_threads(mut self, threads: usize) -> RioBuilder {
+        self.executor_threads = threads;
+        self
+    }
+
+    /// The clock used to schedule the timers.
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> RioBuilder {
+        self.clock = clock;
+        self
+    }
+
+    /// Create the loop.
+    pub fn build(self) -> io::Result<Rio> {
+        if self.events_capacity == 0 || self.read_buffer_size == 0 || self.executor_threads == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "The events capacity, the read buffer size and the executor threads cannot be 0"));
+        }
+        let poll = Poll::new()?;
+        Ok(Rio {
+            running: false,
+            hub: Hub::new(),
+            wakeups: Rc::new(RefCell::new(Vec::new())),
+            poll,
+            connections: Slab::with_capacity(self.connections_capacity),
+            servers: Vec::new(),
+            processes: Vec::new(),
+            signals: None,
+            stopper: None,
+            executor: None,
+            executor_threads: self.executor_threads,
+            signal_handlers: Vec::new(),
+            clients: 0,
+            max_connections: self.max_connections.unwrap_or(self.connections_capacity),
+            events_capacity: self.events_capacity,
+            poll_timeout: self.poll_timeout,
+            read_buffer: vec![0; self.read_buffer_size],
+            draining: false,
+            clock: self.clock,
+            timers: BTreeMap::new(),
+            next_timer_id: 0,
+        })
+    }
+}
+
+
+impl Default for RioBuilder {
+    fn default() -> RioBuilder {
+        RioBuilder::new()
+    }
+}
+
+
+/// The I/O Loop
+pub struct Rio {
+    poll: Poll,
+    connections: Slab<Connection>,
+    servers: Vec<Token>,
+    processes: Vec<Token>,
+    signals: Option<Token>,
+    stopper: Option<Stopper>,
+    executor: Option<Token>,
+    executor_threads: usize,
+    signal_handlers: Vec<(Signal, SignalHandler)>,
+    clients: usize,
+    max_connections: usize,
+    events_capacity: usize,
+    poll_timeout: Duration,
+    read_buffer: Vec<u8>,
+    draining: bool,
+    clock: Box<dyn Clock>,
+    timers: BTreeMap<Timer, TimerCallback>,
+    next_timer_id: usize,
+    hub: Hub,
+    wakeups: Rc<RefCell<Vec<Token>>>,
+    running: bool
+}
+
+
+impl Default for Rio {
+    fn default() -> Rio {
+        Rio::new()
+    }
+}
+
+
+impl Rio {
+    /// Instanciate the IOLoop, should be called once.
+    pub fn new() -> Rio {
+        Rio::with_clock(Box::new(SystemClock))
+    }
+
+    /// Instanciate the IOLoop with the clock used to schedule the timers.
+    pub fn with_clock(clock: Box<dyn Clock>) -> Rio {
+        RioBuilder::new().clock(clock).build().unwrap()
+    }
+
+    /// Will listen on the given address when the loop will start.
+    /// The ServerFactory.build_protocol method will be called on every
+    /// new client connection.
+    pub fn listen(&mut self, addr: &str, server: Box<dyn ServerFactory>) -> Result<Listener, io::Error> {
+        info!("Rio is listenning on {}", addr);
+        let sock_addr = parse_addr(addr)?;
+        debug!("Bind the server socket {}", addr);
+        let sock = TcpListener::bind(&sock_addr)?;
+        self.register_server(server, sock, false)
+    }
+
+    /// Will listen using the options of the builder, such as `SO_REUSEPORT`
+    /// or the backlog size.
+    pub fn listen_with(&mut self, builder: &ListenerBuilder, server: Box<dyn ServerFactory>) -> Result<Listener, io::Error> {
+        info!("Rio is listenning on {}", builder.addr());
+        let listener = builder.bind()?;
+        let sock_addr = listener.local_addr()?;
+        let sock = TcpListener::from_listener(listener, &sock_addr)?;
+        self.register_server(server, sock, builder.has_proxy_protocol())
+    }
+
+    /// Will listen on an already bound and listening socket, such as
+    /// the one inherited by systemd socket activation.
+    /// The loop takes the ownership of the file descriptor.
+    pub fn listen_fd(&mut self, fd: RawFd, server: Box<dyn ServerFactory>) -> Result<Listener, io::Error> {
+        let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+        let sock_addr = listener.local_addr()?;
+        info!("Rio is listenning on {} from fd {}", sock_addr, fd);
+        let sock = TcpListener::from_listener(listener, &sock_addr)?;
+        self.register_server(server, sock, false)
+    }
+
+    fn register_server(&mut self,
+                       server: Box<dyn ServerFactory>,
+                       sock: TcpListener,
+                       proxy_protocol: bool)
+                       -> Result<Listener, io::Error> {
+        let addr = sock.local_addr()?;
+        let result = self.connections.insert(Connection::new_server(server, addr, sock, proxy_protocol));
+        match result {
+            Ok(token) => {
+                let _ = self.poll.register(self.connections[token].server_ref().socket()?,
+                                           token,
+                                           Ready::readable() | Ready::writable(),
+                                           PollOpt::edge());
+                self.servers.push(token);
+                info!("Server {:?} bound to {}", token, addr);
+                Ok(Listener::new(token, addr))
+            }
+            Err(_) => {
+                error!("Cannot register server {:?}", addr);
+                Err(io::Error::other(format!("Cannot register server {:?}", addr)))
+            }
+        }
+    }
+
+    /// The local address of a server or a client connection.
+    pub fn local_addr(&self, token: Token) -> io::Result<SocketAddr> {
+        if !self.connections.contains(token) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No connection {:?}", token)));
+        }
+        let connection = &self.connections[token];
+        match connection.connection_type {
+            ConnectionType::Server => connection.server_ref().socket()?.local_addr(),
+            ConnectionType::Client => connection.client_ref().socket.local_addr(),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a socket", token))),
+        }
+    }
+
+    /// The maximum number of connections open at the same time on the loop,
+    /// accepted or connected. Once reached, the servers apply their
+    /// `ServerFactory.overload_policy`.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    /// The number of connections open on the loop, accepted or connected.
+    pub fn connections_count(&self) -> usize {
+        self.clients
+    }
+
+    /// Stop accepting connections on the listener, its socket is closed
+    /// while the other servers keep running.
+    /// The `on_drained` callback is called once every connection accepted
+    /// by the listener is closed, immediately if there is none.
+    pub fn close_listener(&mut self,
+                          token: Token,
+                          on_drained: Option<DrainCallback>)
+                          -> io::Result<()> {
+        let is_server = self.connections.contains(token) &&
+                        matches!(self.connections[token].connection_type, ConnectionType::Server);
+        if !is_server {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No listener {:?}", token)));
+        }
+        {
+            let server = self.connections[token].server_mut();
+            let socket = server.socket.take()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Listener already closed"))?;
+            self.poll.deregister(&socket)?;
+            server.on_drained = on_drained;
+            info!("Listener {:?} closed, {} connections remaining", token, server.connections);
+        }
+        self.servers.retain(|server| *server != token);
+        self.release_listener(token);
+        Ok(())
+    }
+
+    /// Remove a closed listener once its last connection is closed.
+    fn release_listener(&mut self, token: Token) {
+        let drained = {
+            let server = self.connections[token].server_ref();
+            server.is_closed() && server.connections == 0
+        };
+        if drained {
+            info!("Listener {:?} drained", token);
+            let server = self.connections.remove(token).and_then(|connection| connection.server);
+            if let Some(on_drained) = server.and_then(|server| server.on_drained) {
+                on_drained(self);
+            }
+        }
+    }
+
+    fn remove_client(&mut self, token: Token) {
+        let client = self.connections.remove(token).and_then(|connection| connection.client);
+        if let Some(client) = client {
+            self.clients -= 1;
+            self.hub.leave_all(token);
+            if let Some(executor) = self.executor {
+                self.connections[executor].executor_mut().cancel(token);
+            }
+            if let Some(listener) = client.listener {
+                self.connections[listener].server_mut().connections -= 1;
+                self.release_listener(listener);
+            }
+            if let Some(linked) = client.transport.linked() {
+                if self.is_client(linked) {
+                    debug!("Hanging up {:?}, linked to the closed {:?}", linked, token);
+                    self.connections[linked].client_mut().transport.set_linked(None);
+                    self.hang_up_client(linked);
+                }
+            }
+        }
+        self.resume_listeners();
+    }
+
+    /// True if the server cannot accept more connections.
+    fn is_overloaded(&self, token: Token) -> bool {
+        let server = self.connections[token].server_ref();
+        let max_connections = server.server.max_connections();
+        self.clients >= self.max_connections ||
+        max_connections.map(|max| server.connections >= max).unwrap_or(false)
+    }
+
+    /// Accept the connections waiting in the backlog of the paused servers
+    /// that are not overloaded anymore.
+    fn resume_listeners(&mut self) {
+        let servers = self.servers.clone();
+        for token in servers {
+            if self.connections[token].server_ref().paused && !self.is_overloaded(token) {
+                info!("Resume accepting connections on {:?}", token);
+                self.connections[token].server_mut().paused = false;
+                let _ = self.handle_server(token);
+            }
+        }
+    }
+
+    /// Will connect to the given address when the loop will start.
+    /// The Protocol.connection_made method is called once connected,
+    /// or the Protocol.connection_failed method if the connect fails.
+    pub fn connect(&mut self, addr: &str, client: Box<dyn Protocol>) -> Result<Token, io::Error> {
+        self.connect_with(addr, client, &SocketOptions::default())
+    }
+
+    /// Will connect to the given address, the options are applied
+    /// on the socket before the Protocol.connection_made method is called.
+    pub fn connect_with(&mut self,
+                        addr: &str,
+                        client: Box<dyn Protocol>,
+                        options: &SocketOptions)
+                        -> Result<Token, io::Error> {
+        info!("Connecting to socket {}", addr);
+        let sock_addr = parse_addr(addr)?;
+        self.connect_addr(sock_addr, client, options, None, None)
+    }
+
+    /// Will connect to the given address like `connect`, and write the
+    /// PROXY protocol header before the data of the protocol.
+    pub fn connect_proxied(&mut self,
+                           addr: &str,
+                           client: Box<dyn Protocol>,
+                           header: &ProxyHeader,
+                           version: ProxyVersion)
+                           -> Result<Token, io::Error> {
+        info!("Connecting to socket {} with the PROXY protocol header {:?}", addr, header);
+        let sock_addr = parse_addr(addr)?;
+        self.connect_addr(sock_addr, client, &SocketOptions::default(), None, Some(header.encode(version)))
+    }
+
+    /// Will connect to the target through the proxy server, the target is
+    /// an address or a name resolved by the server, with its port.
+    /// The Protocol.connection_made method is called once the server
+    /// connected to the target.
+    pub fn connect_through(&mut self,
+                           proxy: &dyn Tunnel,
+                           target: &str,
+                           client: Box<dyn Protocol>)
+                           -> Result<Token, io::Error> {
+        info!("Connecting to {} through the proxy server {}", target, proxy.addr());
+        let client = proxy.tunnel(target, client)?;
+        self.connect_addr(proxy.addr(), client, &SocketOptions::default(), None, None)
+    }
+
+    fn connect_addr(&mut self,
+                    addr: SocketAddr,
+                    client: Box<dyn Protocol>,
+                    options: &SocketOptions,
+                    linked: Option<Token>,
+                    proxy_header: Option<Vec<u8>>)
+                    -> Result<Token, io::Error> {
+        let sock = TcpStream::connect(&addr)?;
+        if let Err(err) = options.apply(sock.as_raw_fd()) {
+            error!("Cannot set socket options of {}: {}", addr, err);
+        }
+        let result = self.connections.insert(Connection::new_client(client, addr, sock, None));
+        match result {
+            Ok(token) => {
+                self.clients += 1;
+                {
+                    let client = self.connections[token].client_mut();
+                    client.transport.set_token(token);
+                    client.transport.set_linked(linked);
+                    if let Some(proxy_header) = proxy_header {
+                        client.transport.write_owned(proxy_header);
+                    }
+                    // the connection is made once the connect completes
+                    client.connecting = true;
+                    let _ = self.poll.register(&client.socket,
+                                               token,
+                                               Ready::all(),
+                                               PollOpt::all());
+                }
+                debug!(" socket {} registered in the poller", addr);
+                self.flush_actions(token);
+                Ok(token)
+            }
+            Err(_) => {
+                error!("Cannot register client {:?}", addr);
+                Err(io::Error::other(format!("Cannot register client {:?}", addr)))
+            }
+        }
+    }
+
+    /// Close the connection once its pending data is written, even if
+    /// its connect did not complete yet.
+    pub fn hang_up(&mut self, token: Token) -> io::Result<()> {
+        if !self.is_client(token) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No connection {:?}", token)));
+        }
+        self.hang_up_client(token);
+        Ok(())
+    }
+
+    /// Spawn the command with its standard input, output and error piped
+    /// to the loop. The SubprocessProtocol.pipe_data_received method will
+    /// be called on every bytes written by the process.
+    /// The exit of the process wakes the loop up with SIGCHLD, blocked for
+    /// the current thread like the signals of `add_signal_handler`. If it
+    /// is delivered to another thread, the process is reaped up to the
+    /// poll timeout later.
+    pub fn spawn(&mut self, mut command: Command, protocol: Box<dyn SubprocessProtocol>) -> Result<Token, io::Error> {
+        // blocked before the process may exit
+        let signals = self.signal_connection()?;
+        self.connections[signals].signal_mut().watch_children()?;
+        let child = command.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        info!("Process {} spawned", child.id());
+
+        let result = self.connections.insert(Connection::new_process(protocol, child));
+        let token = match result {
+            Ok(token) => token,
+            Err(mut connection) => {
+                error!("Cannot register process");
+                let _ = connection.process_mut().child.kill();
+                let _ = connection.process_mut().child.wait();
+                return Err(io::Error::other("Cannot register process"));
+            }
+        };
+        self.processes.push(token);
+
+        for fd in &[STDIN, STDOUT, STDERR] {
+            let raw_fd = self.connections[token].process_mut().raw_fd(*fd).unwrap();
+            set_nonblocking(raw_fd)?;
+            let pipe_token = match self.connections.insert(Connection::new_pipe(token, *fd)) {
+                Ok(pipe_token) => pipe_token,
+                Err(_) => {
+                    error!("Cannot register pipe {}", fd);
+                    self.connections[token].process_mut().close_pipe(*fd);
+                    continue;
+                }
+            };
+            let interest = if *fd == STDIN { Ready::writable() } else { Ready::readable() };
+            self.poll.register(&EventedFd(&raw_fd), pipe_token, interest, PollOpt::edge())?;
+            self.connections[token].process_mut().pipes.push(pipe_token);
+        }
+
+        {
+            let process = self.connections[token].process_mut();
+            process.protocol.connection_made(&mut process.transport);
+        }
+        self.flush_stdin(token)?;
+        Ok(token)
+    }
+
+    /// Call the handler every time the signal is received, instead of
+    /// its default action. The signal is blocked for the current thread,
+    /// it should be added before any thread is started to be delivered
+    /// to the loop.
+    pub fn add_signal_handler(&mut self, signal: Signal, handler: Box<dyn FnMut(&mut Rio)>) -> Result<(), io::Error> {
+        info!("Add signal handler for {:?}", signal);
+        let token = self.signal_connection()?;
+        self.connections[token].signal_mut().add(signal)?;
+        self.signal_handlers.retain(|&(handled, _)| handled != signal);
+        self.signal_handlers.push((signal, handler));
+        Ok(())
+    }
+
+    /// Restore the default action of the signal, SIGCHLD stays blocked
+    /// once a process has been spawned.
+    /// Return false if no handler was registered for the signal.
+    pub fn remove_signal_handler(&mut self, signal: Signal) -> Result<bool, io::Error> {
+        let token = match self.signals {
+            Some(token) if self.connections[token].signal_mut().handled.contains(signal) => token,
+            _ => return Ok(false),
+        };
+        info!("Remove signal handler for {:?}", signal);
+        self.signal_handlers.retain(|&(handled, _)| handled != signal);
+        self.connections[token].signal_mut().remove(signal)?;
+        Ok(true)
+    }
+
+    /// The signal file descriptor, registered on the first call.
+    fn signal_connection(&mut self) -> io::Result<Token> {
+        if let Some(token) = self.signals {
+            return Ok(token);
+        }
+        let connection = Connection::new_signal(SignalConnection::new()?);
+        let token = match self.connections.insert(connection) {
+            Ok(token) => token,
+            Err(_) => {
+                error!("Cannot register the signal file descriptor");
+                return Err(io::Error::other("Cannot register the signal file descriptor"));
+            }
+        };
+        let fd = self.connections[token].signal_mut().fd.as_raw_fd();
+        self.poll.register(&EventedFd(&fd), token, Ready::readable(), PollOpt::edge())?;
+        self.signals = Some(token);
+        Ok(token)
+    }
+
+    /// Hand the listening sockets over to a new process, for a restart
+    /// without downtime.
+    /// The command is spawned with the listening sockets inherited, their
+    /// file descriptors are listed in the `JANEIRO_LISTEN_FDS` environment
+    /// variable, to be adopted with `activation::handover_fds`.
+    /// Then the loop stops accepting connections, and stops once every
+    /// established connection has been closed.
+    pub fn handover(&mut self, mut command: Command) -> Result<Child, io::Error> {
+        let fds: Vec<RawFd> = self.servers.iter()
+            .map(|token| self.connections[*token].server_ref().socket().map(|socket| socket.as_raw_fd()))
+            .collect::<io::Result<Vec<RawFd>>>()?;
+        info!("Handing over {} listening sockets", fds.len());
+
+        // The sockets are inherited by the child if close-on-exec is unset.
+        for fd in &fds {
+            fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+        }
+        let env = fds.iter().map(|fd| fd.to_string()).collect::<Vec<String>>().join(",");
+        let result = command.env(HANDOVER_FDS, env).spawn();
+        for fd in &fds {
+            fcntl(*fd, FcntlArg::F_SETFD(FD_CLOEXEC))?;
+        }
+        let child = result?;
+        info!("Process {} spawned, draining connections", child.id());
+
+        let servers = self.servers.clone();
+        for token in servers {
+            self.close_listener(token, None)?;
+        }
+        self.draining = true;
+        Ok(child)
+    }
+
+    /// Stop the io loop after the current iteration.
+    pub fn stop(&mut self) {
+        info!("Stop requested");
+        self.running = false;
+    }
+
+    /// A handle to stop the loop from another thread.
+    pub fn stopper(&mut self) -> io::Result<Stopper> {
+        if let Some(ref stopper) = self.stopper {
+            return Ok(stopper.clone());
+        }
+        let (sender, receiver) = channel::channel();
+        let token = match self.connections.insert(Connection::new_stop(receiver)) {
+            Ok(token) => token,
+            Err(_) => {
+                error!("Cannot register the stopper");
+                return Err(io::Error::other("Cannot register the stopper"));
+            }
+        };
+        if let Err(err) = self.poll.register(self.connections[token].stop_ref(), token, Ready::readable(), PollOpt::edge()) {
+            self.connections.remove(token);
+            return Err(err);
+        }
+        let stopper = Stopper { sender };
+        self.stopper = Some(stopper.clone());
+        Ok(stopper)
+    }
+
+    /// The current time of the loop clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Call the callback once the delay is elapsed, on the loop.
+    pub fn call_later(&mut self, delay: Duration, callback: Box<dyn FnOnce(&mut Rio)>) -> Timer {
+        let timer = Timer {
+            deadline: self.clock.now() + delay,
+            id: self.next_timer_id,
+        };
+        self.next_timer_id += 1;
+        debug!("Schedule {:?}", timer);
+        self.timers.insert(timer, callback);
+        timer
+    }
+
+    /// Cancel a timer, return false if it has already been called
+    /// or cancelled.
+    pub fn cancel_timer(&mut self, timer: Timer) -> bool {
+        debug!("Cancel {:?}", timer);
+        self.timers.remove(&timer).is_some()
+    }
+
+    /// Start the io loop
+    pub fn run_forever(&mut self) {
+        self.run_until(&|_: &Rio| -> bool { true });
+    }
+
+    pub fn run_until(&mut self, is_done: &dyn Fn(&Rio) -> bool) {
+
+        info!("Start polling");
+
+        let mut events = Events::with_capacity(self.events_capacity);
+
+        self.running = true;
+        while self.running {
+            self.turn(&mut events);
+            if self.draining && self.is_drained() {
+                info!("Every connection has been closed");
+                self.running = false;
+            }
+            self.running = self.running && is_done(self)
+        }
+    }
+
+    /// Run a single iteration of the loop, waiting for events
+    /// until the next timer is due.
+    pub fn run_once(&mut self) {
+        let mut events = Events::with_capacity(self.events_capacity);
+        self.running = true;
+        self.turn(&mut events);
+        self.running = false;
+    }
+
+    fn turn(&mut self, events: &mut Events) {
+        // debug!("Polling...");
+        let timeout = self.next_timeout();
+        self.poll.poll(events, Some(timeout)).unwrap();
+
+        for event in events.iter() {
+            let token = event.token();
+            debug!("Got event for {:?}", token);
+            if !self.connections.contains(token) {
+                debug!("Ignoring event of a removed connection {:?}", token);
+                continue;
+            }
+            let _ = match self.connections[token].connection_type {
+                ConnectionType::Server => self.handle_server(token),
+                ConnectionType::Client => self.handle_client(token, event),
+                ConnectionType::Pipe => self.handle_pipe(token),
+                ConnectionType::Signal => self.handle_signal(token),
+                ConnectionType::Task => self.handle_task(token),
+                ConnectionType::Executor => self.handle_executor(token),
+                ConnectionType::Stop => self.handle_stop(token),
+                ConnectionType::Process => Ok(()),
+            };
+        }
+        self.reap_processes();
+        self.handle_timers();
+        self.handle_wakeups();
+    }
+
+    fn next_timeout(&self) -> Duration {
+        let timeout = self.poll_timeout;
+        // a virtual clock does not move while the loop sleeps
+        if !self.wakeups.borrow().is_empty() || self.clock.is_virtual() {
+            return Duration::from_millis(0);
+        }
+        match self.timers.keys().next() {
+            Some(timer) => {
+                let now = self.clock.now();
+                if timer.deadline <= now {
+                    Duration::from_millis(0)
+                } else {
+                    cmp::min(timeout, timer.deadline - now)
+                }
+            }
+            None => timeout,
+        }
+    }
+
+    fn handle_timers(&mut self) {
+        let now = self.clock.now();
+        // timers scheduled by the callbacks are called on the next iteration
+        let due: Vec<Timer> = self.timers.keys().take_while(|timer| timer.deadline <= now).cloned().collect();
+        for timer in due {
+            if let Some(callback) = self.timers.remove(&timer) {
+                debug!("Call {:?}", timer);
+                callback(self);
+            }
+        }
+    }
+
+
+    /// True if the connection is registered, a closed listener is not
+    /// anymore, even while its connections are draining.
+    pub fn contains(&self, token: Token) -> bool {
+        if !self.connections.contains(token) {
+            return false;
+        }
+        match self.connections[token].connection_type {
+            ConnectionType::Server => !self.connections[token].server_ref().is_closed(),
+            _ => true,
+        }
+    }
+
+    fn is_drained(&self) -> bool {
+        !self.connections.iter().any(|connection| matches!(connection.connection_type, ConnectionType::Client))
+    }
+
+    fn close_pipe(&mut self, process_token: Token, pipe_token: Token) -> io::Result<()> {
+        let fd = self.connections[pipe_token].pipe_ref().fd;
+        {
+            let process = self.connections[process_token].process_mut();
+            if let Some(raw_fd) = process.raw_fd(fd) {
+                self.poll.deregister(&EventedFd(&raw_fd))?;
+            }
+            process.close_pipe(fd);
+            process.pipes.retain(|pipe| *pipe != pipe_token);
+        }
+        self.connections.remove(pipe_token);
+        Ok(())
+    }
+
+    fn flush_stdin(&mut self, process_token: Token) -> io::Result<()> {
+        if !self.connections[process_token].process_mut().handle_write() {
+            return Ok(());
+        }
+        let stdin_token = self.connections[process_token].process_ref().pipes.iter().cloned()
+            .find(|pipe| self.connections[*pipe].pipe_ref().fd == STDIN);
+        match stdin_token {
+            Some(pipe_token) => self.close_pipe(process_token, pipe_token),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_pipe(&mut self, token: Token) -> io::Result<()> {
+        let (process_token, fd) = {
+            let pipe = self.connections[token].pipe_ref();
+            (pipe.process, pipe.fd)
+        };
+        debug!("handle pipe {} of process {:?}", fd, process_token);
+
+        if fd != STDIN && self.connections[process_token].process_mut().handle_read(fd, &mut self.read_buffer) {
+            self.close_pipe(process_token, token)?;
+        }
+        self.flush_stdin(process_token)
+    }
+
+    fn handle_signal(&mut self, token: Token) -> io::Result<()> {
+        let signals = self.connections[token].signal_mut().read_signals();
+        for signal in signals {
+            info!("Received signal {:?}", signal);
+            let position = self.signal_handlers.iter().position(|&(handled, _)| handled == signal);
+            let (_, mut handler) = match position {
+                Some(position) => self.signal_handlers.remove(position),
+                // the processes are reaped at the end of the iteration
+                None if signal == Signal::SIGCHLD => continue,
+                None => {
+                    error!("No handler for signal {:?}", signal);
+                    continue;
+                }
+            };
+            handler(self);
+            // the handler may have been replaced or removed while running
+            if self.connections[token].signal_mut().handled.contains(signal) &&
+               !self.signal_handlers.iter().any(|&(handled, _)| handled == signal) {
+                self.signal_handlers.push((signal, handler));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_stop(&mut self, token: Token) -> io::Result<()> {
+        let mut stopped = false;
+        while self.connections[token].stop_ref().try_recv().is_ok() {
+            stopped = true;
+        }
+        if stopped {
+            self.stop();
+        }
+        Ok(())
+    }
+
+    fn reap_processes(&mut self) {
+        let mut exited = Vec::new();
+        for token in &self.processes {
+            let process = self.connections[*token].process_mut();
+            match process.child.try_wait() {
+                Ok(Some(status)) => exited.push((*token, status)),
+                Ok(None) => {}
+                Err(err) => error!("Cannot wait process {}: {}", process.child.id(), err),
+            }
+        }
+
+        for (token, status) in exited {
+            info!("Process {} exited with {}", self.connections[token].process_ref().child.id(), status);
+            let pipes = self.connections[token].process_ref().pipes.clone();
+            for pipe_token in pipes {
+                let fd = self.connections[pipe_token].pipe_ref().fd;
+                if fd != STDIN {
+                    self.connections[token].process_mut().handle_read(fd, &mut self.read_buffer);
+                }
+                if let Err(err) = self.close_pipe(token, pipe_token) {
+                    error!("Cannot deregister pipe {}: {}", fd, err);
+                }
+            }
+            self.connections[token].process_mut().protocol.process_exited(status);
+            self.processes.retain(|process| *process != token);
+            self.connections.remove(token);
+        }
+    }
+
+    fn handle_server(&mut self, token: Token) -> io::Result<()> {
+        while self.running {
+            let overloaded = self.is_overloaded(token);
+            let policy = self.connections[token].server_ref().server.overload_policy();
+            if overloaded && policy == OverloadPolicy::Pause {
+                let server = self.connections[token].server_mut();
+                if !server.paused {
+                    info!("Pause accepting connections on {:?}, limit reached", token);
+                    server.paused = true;
+                    server.server.limit_reached(server.connections);
+                }
+                return Ok(());
+            }
+
+            let (sock, addr) = self.connections[token].server_ref().socket()?.accept()?;
+
+            if let OverloadPolicy::Reject(ref message) = policy {
+                if overloaded {
+                    info!("Rejecting connection from {:?}, limit reached", addr);
+                    let server = self.connections[token].server_ref();
+                    server.server.limit_reached(server.connections);
+                    reject(sock, message);
+                    continue;
+                }
+            }
+
+            info!("Accepting connection from {:?}", addr);
+
+            debug!("Building procotol");
+            let (protocol, options, proxy_protocol) = {
+                let server = self.connections[token].server_ref();
+                (server.server.build_protocol(), server.server.socket_options(), server.proxy_protocol)
+            };
+            if let Err(err) = options.apply(sock.as_raw_fd()) {
+                error!("Cannot set socket options of {:?}: {}", addr, err);
+            }
+
+            debug!("Take a token for the connection");
+            let result = self.connections.insert(Connection::new_client(protocol, addr, sock, Some(token)));
+            match result {
+                Ok(client_token) => {
+                    self.clients += 1;
+                    self.connections[token].server_mut().connections += 1;
+                    debug!("Registering procotol");
+                    {
+                        let client = self.connections[client_token].client_mut();
+                        client.transport.set_token(client_token);
+                        if proxy_protocol {
+                            debug!("Waiting for the PROXY protocol header");
+                            client.proxy_header = Some(Vec::new());
+                        } else {
+                            client.protocol.connection_made(&mut client.transport);
+                        }
+                        self.poll.register(&client.socket,
+                                           client_token,
+                                           Ready::readable() | Ready::writable(),
+                                           PollOpt::edge() | PollOpt::oneshot())?;
+                    }
+                    self.flush_actions(client_token);
+                }
+                Err(connection) => {
+                    error!("Cannot register client {:?}, closing it", addr);
+                    if let Some(client) = connection.client {
+                        reject(client.socket, b"");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_client(&self, token: Token) -> bool {
+        self.connections.contains(token) &&
+        matches!(self.connections[token].connection_type, ConnectionType::Client)
+    }
+
+    /// Write the pending data of the client, and close it if finished.
+    fn update_client(&mut self, token: Token) -> io::Result<()> {
+        if !self.is_client(token) {
+            return Ok(());
+        }
+        {
+            let client = self.connections[token].client_mut();
+            if !client.is_finished() {
+                client.handle_write(&mut self.read_buffer);
+            }
+            match client.transport.update_writing() {
+                Some(true) => client.protocol.pause_writing(&mut client.transport),
+                Some(false) => client.protocol.resume_writing(&mut client.transport),
+                None => {}
+            }
+        }
+        self.flush_actions(token);
+        if !self.is_client(token) {
+            return Ok(());
+        }
+
+        let finished = {
+            let client = self.connections[token].client_mut();
+            if client.is_finished() {
+                match client.peer_addr() {
+                    Ok(addr) => info!("Connection closed {:?}", addr),
+                    Err(_) => error!("Connection closed (Peer already disconnected)"),
+                }
+                let reason = client.reason();
+                info!("Connection {:?} closed: {:?}", token, reason);
+                if client.is_made() {
+                    client.protocol.connection_lost(reason);
+                }
+                if let Err(err) = self.poll.deregister(&client.socket) {
+                    error!("Cannot deregister the connection {:?}: {}", token, err);
+                }
+                true
+            } else {
+                let interest = client.poll_interest();
+                // mio needs readable or writable, the current registration
+                // is kept and its events are ignored until reading resumes
+                let result = if interest.is_readable() || interest.is_writable() {
+                    debug!("Reregister token {:?}", token);
+                    self.poll.reregister(&client.socket, token, interest, PollOpt::edge())
+                } else {
+                    Ok(())
+                };
+                match result {
+                    Ok(()) => false,
+                    Err(err) => {
+                        error!("Cannot reregister the connection {:?}: {}", token, err);
+                        if client.is_made() {
+                            client.protocol.connection_lost(Reason::ConnectionError);
+                        }
+                        true
+                    }
+                }
+            }
+        };
+        if finished {
+            info!("Removing connection {:?}", token);
+            self.remove_client(token);
+        }
+        Ok(())
+    }
+
+    /// Do the actions requested by the protocol on the other connections.
+    fn flush_actions(&mut self, token: Token) {
+        if !self.is_client(token) {
+            return;
+        }
+        let actions = self.connections[token].client_mut().transport.take_actions();
+        for action in actions {
+            let target = match action {
+                Action::Connect(addr, protocol, proxy_header) => {
+                    info!("Connecting {:?} to {}", token, addr);
+                    match self.connect_addr(addr, protocol, &SocketOptions::default(), Some(token), proxy_header) {
+                        Ok(linked) if self.is_client(token) => {
+                            self.connections[token].client_mut().transport.set_linked(Some(linked));
+                        }
+                        Ok(linked) => {
+                            debug!("{:?} closed while connecting, hanging up {:?}", token, linked);
+                            self.hang_up_client(linked);
+                        }
+                        Err(err) => {
+                            // the protocol waits for the linked connection
+                            error!("Cannot connect {:?} to {}: {}", token, addr, err);
+                            self.hang_up_client(token);
+                        }
+                    }
+                    continue;
+                }
+                Action::Join(member, channel) => {
+                    debug!("{:?} joins the channel {}", member, channel);
+                    self.hub.join(&channel, member);
+                    continue;
+                }
+                Action::Leave(member, channel) => {
+                    debug!("{:?} leaves the channel {}", member, channel);
+                    self.hub.leave(&channel, member);
+                    continue;
+                }
+                Action::Publish(channel, data) => {
+                    self.broadcast(&channel, data, Some(token));
+                    continue;
+                }
+                Action::RunInExecutor(job) => {
+                    if let Err(err) = self.submit_job(token, job) {
+                        error!("Cannot run the job of {:?} in the executor: {}", token, err);
+                    }
+                    continue;
+                }
+                Action::Write(target, _) | Action::WriteEof(target) | Action::HangUp(target) |
+                Action::Wake(target) | Action::PauseReading(target) | Action::ResumeReading(target) => target,
+            };
+            if !self.is_client(target) {
+                debug!("Ignoring an action of {:?} on the closed connection {:?}", token, target);
+                continue;
+            }
+            {
+                let client = self.connections[target].client_mut();
+                let made = client.is_made();
+                let transport = &mut client.transport;
+                match action {
+                    Action::Write(_, data) => transport.write_owned(data),
+                    Action::WriteEof(_) => transport.write_eof(),
+                    Action::HangUp(_) => transport.hang_up(),
+                    Action::Wake(_) if made => client.protocol.wakeup(transport),
+                    Action::Wake(_) => {}
+                    Action::PauseReading(_) => transport.pause_reading(),
+                    Action::ResumeReading(_) => transport.resume_reading(),
+                    Action::Connect(..) | Action::Join(..) | Action::Leave(..) | Action::Publish(..) |
+                    Action::RunInExecutor(..) => {}
+                }
+            }
+            if target != token {
+                self.flush_actions(target);
+            }
+            if let Err(err) = self.update_client(target) {
+                error!("Cannot update the connection {:?}: {}", target, err);
+            }
+        }
+    }
+
+    /// Write the data to every member of the channel, joined with
+    /// `Transport::join`. Return the number of members it is written to.
+    pub fn publish(&mut self, channel: &str, data: &[u8]) -> usize {
+        self.broadcast(channel, Arc::from(data), None)
+    }
+
+    /// The connections that joined the channel, in the order they joined.
+    pub fn channel_members(&self, channel: &str) -> Vec<Token> {
+        self.hub.members(channel).to_vec()
+    }
+
+    /// The channels joined by the connection.
+    pub fn joined_channels(&self, token: Token) -> Vec<String> {
+        self.hub.channels(token).to_vec()
+    }
+
+    fn broadcast(&mut self, channel: &str, data: Arc<[u8]>, publisher: Option<Token>) -> usize {
+        let members = self.hub.members(channel).to_vec();
+        debug!("Publishing {} bytes to the {} members of {}", data.len(), members.len(), channel);
+        let mut written = 0;
+        for member in members {
+            // a member closed by a previous write left the channel,
+            // and its token may already be used by another connection
+            if !self.hub.is_member(channel, member) || !self.is_client(member) {
+                continue;
+            }
+            self.connections[member].client_mut().transport.write_shared(data.clone());
+            written += 1;
+            // the publisher is updated once its protocol method returns
+            if Some(member) != publisher {
+                if let Err(err) = self.update_client(member) {
+                    error!("Cannot update the connection {:?}: {}", member, err);
+                }
+            }
+        }
+        written
+    }
+
+    /// Call the `wakeup` method of the protocol of the connection, to
+    /// write the data queued outside of the protocol methods, such as
+    /// in a timer callback.
+    pub fn wake(&mut self, token: Token) -> io::Result<()> {
+        if !self.is_client(token) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No connection {:?}", token)));
+        }
+        {
+            let client = self.connections[token].client_mut();
+            if client.is_made() {
+                client.protocol.wakeup(&mut client.transport);
+            }
+        }
+        self.flush_actions(token);
+        self.update_client(token)
+    }
+
+    /// A handle to wake the connections, without borrowing the loop.
+    pub fn waker(&self) -> Waker {
+        Waker { tokens: self.wakeups.clone() }
+    }
+
+    fn handle_wakeups(&mut self) {
+        loop {
+            let tokens = mem::take(&mut *self.wakeups.borrow_mut());
+            if tokens.is_empty() {
+                break;
+            }
+            for token in tokens {
+                // the connection may have been closed since
+                if self.is_client(token) {
+                    if let Err(err) = self.wake(token) {
+                        error!("Cannot wake the connection {:?}: {}", token, err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Poll the future on the loop until it is completed, every time
+    /// it is notified, from any thread. It is first polled on the next
+    /// iteration of the loop.
+    pub fn spawn_future<F>(&mut self, future: F) -> io::Result<Token>
+        where F: Future<Item = (), Error = ()> + 'static
+    {
+        let token = match self.connections.vacant_entry() {
+            Some(entry) => entry.index(),
+            None => {
+                error!("Cannot register task");
+                return Err(io::Error::other("Cannot register task"));
+            }
+        };
+        let (registration, readiness) = Registration::new(&self.poll, token, Ready::readable(), PollOpt::edge());
+        readiness.set_readiness(Ready::readable())?;
+        let task = TaskConnection {
+            task: executor::spawn(Box::new(future) as BoxFuture),
+            _registration: registration,
+            notify: Arc::new(TaskNotify { readiness }),
+        };
+        if self.connections.insert(Connection::new_task(task)).is_err() {
+            return Err(io::Error::other("Cannot register task"));
+        }
+        debug!("Task {:?} spawned", token);
+        Ok(token)
+    }
+
+    fn handle_task(&mut self, token: Token) -> io::Result<()> {
+        if self.connections[token].task_mut().poll(token)? {
+            debug!("Task {:?} completed", token);
+            self.connections.remove(token);
+        }
+        Ok(())
+    }
+
+    /// Run the work on a thread of the executor, not to block the loop,
+    /// then call the callback with its result on the loop and wake up
+    /// the protocol of the connection, if it is still open.
+    pub fn run_in_executor<W, T, C>(&mut self, token: Token, work: W, callback: C) -> io::Result<()>
+        where W: FnOnce() -> T + Send + 'static,
+              T: Send + 'static,
+              C: FnOnce(T) + 'static
+    {
+        if !self.is_client(token) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No connection {:?}", token)));
+        }
+        self.submit_job(token, Job::new(work, callback))
+    }
+
+    fn submit_job(&mut self, token: Token, job: Job) -> io::Result<()> {
+        let executor = match self.executor {
+            Some(executor) => executor,
+            None => {
+                info!("Starting the executor with {} threads", self.executor_threads);
+                let connection = Connection::new_executor(ThreadPool::new(self.executor_threads)?);
+                let executor = match self.connections.insert(connection) {
+                    Ok(executor) => executor,
+                    Err(_) => {
+                        error!("Cannot register the executor");
+                        return Err(io::Error::other("Cannot register the executor"));
+                    }
+                };
+                let pool = self.connections[executor].executor_mut();
+                self.poll.register(pool.results(), executor, Ready::readable(), PollOpt::edge())?;
+                self.executor = Some(executor);
+                executor
+            }
+        };
+        self.connections[executor].executor_mut().submit(token, job)
+    }
+
+    fn handle_executor(&mut self, token: Token) -> io::Result<()> {
+        let results = self.connections[token].executor_mut().take_results();
+        for (target, completion, output) in results {
+            // the jobs of the closed connections are cancelled
+            if !self.is_client(target) {
+                continue;
+            }
+            completion(output);
+            if let Err(err) = self.wake(target) {
+                error!("Cannot wake the connection {:?}: {}", target, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn hang_up_client(&mut self, token: Token) {
+        if self.is_client(token) {
+            self.connections[token].client_mut().transport.hang_up();
+            if let Err(err) = self.update_client(token) {
+                error!("Cannot hang up the connection {:?}: {}", token, err);
+            }
+        }
+    }
+
+    /// Make the outgoing connection once the connect completes, or remove
+    /// it if the connect failed. Return false until it is made.
+    fn handle_connect(&mut self, token: Token, kind: Ready) -> bool {
+        let error = match self.connections[token].client_ref().socket.take_error() {
+            Ok(Some(err)) | Err(err) => Some(err),
+            Ok(None) if kind.is_error() || kind.is_hup() => Some(io::Error::other("Cannot connect")),
+            Ok(None) => None,
+        };
+        if let Some(err) = error {
+            info!("Cannot connect {:?} to {:?}: {}", token, self.connections[token].peer_addr, err);
+            {
+                let client = self.connections[token].client_mut();
+                client.protocol.connection_failed(&err, &mut client.transport);
+            }
+            self.flush_actions(token);
+            self.remove_client(token);
+            return false;
+        }
+        if !kind.is_readable() && !kind.is_writable() {
+            return false;
+        }
+        debug!("Connected {:?} to {:?}", token, self.connections[token].peer_addr);
+        let client = self.connections[token].client_mut();
+        client.connecting = false;
+        client.protocol.connection_made(&mut client.transport);
+        true
+    }
+
+    fn handle_client(&mut self, token: Token, event: Event) -> io::Result<()> {
+        debug!("handle client, {:?}", event);
+
+        if !&self.connections.contains(token) {
+            error!("Ignoring unkown token to handle");
+            return Ok(());
+        }
+
+        let kind = event.kind();
+
+        if self.connections[token].client_ref().connecting && !self.handle_connect(token, kind) {
+            return Ok(());
+        }
+
+        // a socket closed by the peer may still have data to read
+        let closed = !kind.is_readable() && !kind.is_hup() && !self.connections[token].alive();
+        if closed || kind.is_error() {
+            error!("Connection failed {:?}", &self.connections[token].peer_addr);
+            info!("Removing connection {:?}",
+                  &self.connections[token].peer_addr);
+
+            {
+                let client = &mut self.connections[token].client_mut();
+                if client.is_made() {
+                    client.protocol.connection_lost(Reason::ConnectionError);
+                }
+            }
+            self.remove_client(token);
+            return Ok(());
+        }
+
+        let client_addr = &self.connections[token].peer_addr.clone();
+        let mut failed = false;
+        {
+            let client = &mut self.connections[token].client_mut();
+
+            debug!("handle client {:?} {:?}", token, client_addr);
+
+            let readable = kind.is_hup() || (kind.is_readable() && !client.transport.reading_paused());
+            if readable && !client.eof {
+                debug!("handle readable {:?} {:?}", token, client_addr);
+                match client.handle_read(&mut self.read_buffer) {
+                    Ok(eof) => {
+                        // the data left once paused is read after resuming
+                        if eof || (kind.is_hup() && !client.transport.reading_paused()) {
+                            debug!("handle hup {:?} {:?}", token, client_addr);
+                            client.handle_eof();
+                        }
+                    }
+                    Err(err) => {
+                        // the connection is gone, such as reset by the peer
+                        error!("Cannot read the connection {:?} {:?}: {}", token, client_addr, err);
+                        if client.is_made() {
+                            client.protocol.connection_lost(Reason::ConnectionError);
+                        }
+                        let _ = self.poll.deregister(&client.socket);
+                        failed = true;
+                    }
+                }
+            }
+        }
+        if failed {
+            info!("Removing connection {:?}", token);
+            self.remove_client(token);
+            return Ok(());
+        }
+
+        self.flush_actions(token);
+        self.update_client(token)?;
 
         debug!("end handle client {:?} {:?}", token, client_addr);
         Ok(())
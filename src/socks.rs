@@ -0,0 +1,671 @@
+//! SOCKS version 5, without the authentication or with a username
+//! and a password, for the CONNECT command only.
+//!
+//! The `Socks5Factory` server forwards the connections with the proxy
+//! once the client sent its request, the names are resolved in the
+//! executor. `Rio::connect_through` connects to a target through a
+//! SOCKS server, the protocol is made once the server connected to the
+//! target.
+
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::str::{self, FromStr};
+
+use mio::Token;
+
+use interface::{Protocol, ServerFactory, Tunnel};
+use proxy::ProxyProtocol;
+use rio::parse_addr;
+use transport::Transport;
+use tunnel::{Handshake, Reply, TunnelClient};
+
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+const CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+
+/// The address to connect to, resolved by the server if it is a name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+
+impl Target {
+    fn parse(target: &str) -> io::Result<Target> {
+        if let Ok(addr) = SocketAddr::from_str(target) {
+            return Ok(Target::Addr(addr));
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid target {}", target));
+        let pos = target.rfind(':').ok_or_else(invalid)?;
+        let port = u16::from_str(&target[pos + 1..]).map_err(|_| invalid())?;
+        let host = &target[..pos];
+        if host.is_empty() || host.len() > 255 {
+            return Err(invalid());
+        }
+        Ok(Target::Domain(host.to_string(), port))
+    }
+
+    /// The address type, the address and the port.
+    fn encode(&self) -> Vec<u8> {
+        let (mut data, port) = match *self {
+            Target::Addr(SocketAddr::V4(addr)) => {
+                let mut data = vec![ATYP_IPV4];
+                data.extend_from_slice(&addr.ip().octets());
+                (data, addr.port())
+            }
+            Target::Addr(SocketAddr::V6(addr)) => {
+                let mut data = vec![ATYP_IPV6];
+                data.extend_from_slice(&addr.ip().octets());
+                (data, addr.port())
+            }
+            Target::Domain(ref host, port) => {
+                let mut data = vec![ATYP_DOMAIN, host.len() as u8];
+                data.extend_from_slice(host.as_bytes());
+                (data, port)
+            }
+        };
+        data.extend_from_slice(&port.to_be_bytes());
+        data
+    }
+
+    /// Parse the address type, the address and the port at the start of
+    /// the data, and return the length read. `Ok(None)` if the data is
+    /// too short, the reply code if the address is not supported.
+    fn decode(data: &[u8]) -> Result<Option<(Target, usize)>, u8> {
+        let (addr_len, skip) = match data.first() {
+            None => return Ok(None),
+            Some(&ATYP_IPV4) => (4, 1),
+            Some(&ATYP_IPV6) => (16, 1),
+            Some(&ATYP_DOMAIN) if data.len() < 2 => return Ok(None),
+            Some(&ATYP_DOMAIN) => (data[1] as usize, 2),
+            Some(_) => return Err(ADDRESS_NOT_SUPPORTED),
+        };
+        let len = skip + addr_len + 2;
+        if data.len() < len {
+            return Ok(None);
+        }
+        let addr = &data[skip..skip + addr_len];
+        let port = u16::from_be_bytes([data[len - 2], data[len - 1]]);
+        let target = match data[0] {
+            ATYP_IPV4 => {
+                let mut ip = [0; 4];
+                ip.copy_from_slice(addr);
+                Target::Addr(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(addr);
+                Target::Addr(SocketAddr::new(IpAddr::from(ip), port))
+            }
+            _ => {
+                let host = str::from_utf8(addr).map_err(|_| ADDRESS_NOT_SUPPORTED)?;
+                Target::Domain(host.to_string(), port)
+            }
+        };
+        Ok(Some((target, len)))
+    }
+
+    /// Resolve the name with the system resolver, blocking the thread.
+    fn resolve(self) -> io::Result<SocketAddr> {
+        match self {
+            Target::Addr(addr) => Ok(addr),
+            Target::Domain(host, port) => {
+                (host.as_str(), port).to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Cannot resolve {}", host)))
+            }
+        }
+    }
+}
+
+
+/// The reply code of the error of the connection to the target.
+fn error_code(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::HostUnreachable => HOST_UNREACHABLE,
+        io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        _ => GENERAL_FAILURE,
+    }
+}
+
+
+fn reply(code: u8) -> Vec<u8> {
+    let mut reply = vec![VERSION, code, 0];
+    reply.extend_from_slice(&Target::Addr(SocketAddr::from(([0, 0, 0, 0], 0))).encode());
+    reply
+}
+
+
+/// Build a `Socks5Protocol` for every accepted connection.
+#[derive(Clone, Debug, Default)]
+pub struct Socks5Factory {
+    users: Rc<Vec<(String, String)>>,
+}
+
+
+impl Socks5Factory {
+    /// A server accepting the clients without authentication.
+    pub fn new() -> Socks5Factory {
+        Socks5Factory::default()
+    }
+
+    /// Accept the user with the password. Once a user is added,
+    /// the clients without authentication are refused.
+    pub fn user(mut self, username: &str, password: &str) -> Socks5Factory {
+        Rc::make_mut(&mut self.users).push((username.to_string(), password.to_string()));
+        self
+    }
+}
+
+
+impl ServerFactory for Socks5Factory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(Socks5Protocol {
+            users: self.users.clone(),
+            state: ServerState::Greeting,
+            buf: Vec::new(),
+            proxy: ProxyProtocol::backend_side(Vec::new()),
+            resolved: Rc::new(RefCell::new(None)),
+        })
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ServerState {
+    Greeting,
+    Auth,
+    Request,
+    Resolving,
+    Forwarding,
+    Closed,
+}
+
+
+/// Read the request of the client, then connect to the target and
+/// forward the connection to it.
+///
+/// The success is replied once the connection to the target is
+/// established, the client is not read until then. If the name cannot
+/// be resolved or the connection fails, the matching error is replied
+/// and the client is hung up.
+pub struct Socks5Protocol {
+    users: Rc<Vec<(String, String)>>,
+    state: ServerState,
+    buf: Vec<u8>,
+    proxy: ProxyProtocol,
+    // the address of the target, once resolved in the executor
+    resolved: Rc<RefCell<Option<io::Result<SocketAddr>>>>,
+}
+
+
+impl Socks5Protocol {
+    fn close(&mut self, transport: &mut Transport, message: &[u8]) {
+        transport.write(message);
+        transport.hang_up();
+        self.state = ServerState::Closed;
+    }
+
+    /// The version and the authentication methods of the client.
+    fn read_greeting(&mut self, transport: &mut Transport) -> Option<usize> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let len = 2 + self.buf[1] as usize;
+        if self.buf.len() < len {
+            return None;
+        }
+        let methods = &self.buf[2..len];
+        let method = if self.buf[0] != VERSION {
+            NO_ACCEPTABLE_METHOD
+        } else if self.users.is_empty() && methods.contains(&NO_AUTH) {
+            NO_AUTH
+        } else if !self.users.is_empty() && methods.contains(&USER_PASSWORD) {
+            USER_PASSWORD
+        } else {
+            NO_ACCEPTABLE_METHOD
+        };
+        match method {
+            NO_AUTH => self.state = ServerState::Request,
+            USER_PASSWORD => self.state = ServerState::Auth,
+            _ => {
+                info!("No acceptable authentication method in {:?}", methods);
+                self.close(transport, &[VERSION, NO_ACCEPTABLE_METHOD]);
+                return None;
+            }
+        }
+        transport.write(&[VERSION, method]);
+        Some(len)
+    }
+
+    /// The username and the password of the client.
+    fn read_auth(&mut self, transport: &mut Transport) -> Option<usize> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let username_len = self.buf[1] as usize;
+        if self.buf.len() < 3 + username_len {
+            return None;
+        }
+        let password_len = self.buf[2 + username_len] as usize;
+        let len = 3 + username_len + password_len;
+        if self.buf.len() < len {
+            return None;
+        }
+        let username = &self.buf[2..2 + username_len];
+        let password = &self.buf[3 + username_len..len];
+        let valid = self.buf[0] == AUTH_VERSION &&
+                    self.users.iter().any(|(user, pass)| user.as_bytes() == username && pass.as_bytes() == password);
+        if !valid {
+            info!("Authentication failed for {:?}", String::from_utf8_lossy(username));
+            self.close(transport, &[AUTH_VERSION, 1]);
+            return None;
+        }
+        transport.write(&[AUTH_VERSION, 0]);
+        self.state = ServerState::Request;
+        Some(len)
+    }
+
+    /// The command and the target, connected once read.
+    fn read_request(&mut self, transport: &mut Transport) -> Option<usize> {
+        if self.buf.len() < 4 {
+            return None;
+        }
+        if self.buf[0] != VERSION {
+            self.close(transport, &reply(GENERAL_FAILURE));
+            return None;
+        }
+        let (target, len) = match Target::decode(&self.buf[3..]) {
+            Ok(Some((target, len))) => (target, 3 + len),
+            Ok(None) => return None,
+            Err(code) => {
+                self.close(transport, &reply(code));
+                return None;
+            }
+        };
+        if self.buf[1] != CONNECT {
+            info!("Command {} not supported", self.buf[1]);
+            self.close(transport, &reply(COMMAND_NOT_SUPPORTED));
+            return None;
+        }
+        // the data sent before the success is forwarded once connected,
+        // the client is not read until then
+        transport.pause_reading();
+        match target {
+            Target::Addr(addr) => {
+                let early_data = self.buf.split_off(len);
+                self.connect(addr, early_data, transport);
+            }
+            target => {
+                // the data after the request is kept until resolved
+                let resolved = self.resolved.clone();
+                transport.run_in_executor(move || target.resolve(),
+                                          move |result| *resolved.borrow_mut() = Some(result));
+                self.state = ServerState::Resolving;
+            }
+        }
+        Some(len)
+    }
+
+    fn connect(&mut self, addr: SocketAddr, early_data: Vec<u8>, transport: &mut Transport) {
+        debug!("Connecting {:?} to {}", transport.token(), addr);
+        transport.connect(addr, Box::new(TargetProtocol {
+            client: transport.token(),
+            proxy: ProxyProtocol::backend_side(early_data),
+        }));
+        self.state = ServerState::Forwarding;
+    }
+}
+
+
+impl Protocol for Socks5Protocol {
+    fn wakeup(&mut self, transport: &mut Transport) {
+        if self.state != ServerState::Resolving {
+            return;
+        }
+        let resolved = self.resolved.borrow_mut().take();
+        match resolved {
+            Some(Ok(addr)) => {
+                let early_data = self.buf.split_off(0);
+                self.connect(addr, early_data, transport);
+            }
+            Some(Err(err)) => {
+                info!("Cannot resolve the target of {:?}: {}", transport.token(), err);
+                self.close(transport, &reply(HOST_UNREACHABLE));
+            }
+            None => {}
+        }
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        if self.state == ServerState::Forwarding {
+            return self.proxy.data_received(data, transport);
+        }
+        self.buf.extend_from_slice(data);
+        loop {
+            let len = match self.state {
+                ServerState::Greeting => self.read_greeting(transport),
+                ServerState::Auth => self.read_auth(transport),
+                ServerState::Request => self.read_request(transport),
+                _ => None,
+            };
+            match len {
+                Some(len) => {
+                    self.buf.drain(..len);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        match self.state {
+            ServerState::Forwarding => self.proxy.eof_received(transport),
+            _ => false,
+        }
+    }
+
+    fn pause_writing(&mut self, transport: &mut Transport) {
+        if self.state == ServerState::Forwarding {
+            self.proxy.pause_writing(transport);
+        }
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        if self.state == ServerState::Forwarding {
+            self.proxy.resume_writing(transport);
+        }
+    }
+}
+
+
+/// The connection to the target, replying to the client once the
+/// connect is done.
+struct TargetProtocol {
+    client: Token,
+    proxy: ProxyProtocol,
+}
+
+
+impl Protocol for TargetProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        {
+            let mut client = transport.peer(self.client);
+            client.write(&reply(SUCCEEDED));
+            client.resume_reading();
+        }
+        self.proxy.connection_made(transport);
+    }
+
+    fn connection_failed(&mut self, error: &io::Error, transport: &mut Transport) {
+        // the client is hung up with this connection
+        transport.peer(self.client).write(&reply(error_code(error)));
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.proxy.data_received(data, transport);
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        self.proxy.eof_received(transport)
+    }
+
+    fn pause_writing(&mut self, transport: &mut Transport) {
+        self.proxy.pause_writing(transport);
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        self.proxy.resume_writing(transport);
+    }
+}
+
+
+/// A SOCKS server to connect through, with `Rio::connect_through`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    addr: SocketAddr,
+    credentials: Option<(String, String)>,
+}
+
+
+impl Socks5Proxy {
+    /// The SOCKS server listening on the address, without authentication.
+    pub fn new(addr: &str) -> io::Result<Socks5Proxy> {
+        let addr = parse_addr(addr)?;
+        Ok(Socks5Proxy {
+            addr,
+            credentials: None,
+        })
+    }
+
+    /// Authenticate with the username and the password.
+    pub fn credentials(mut self, username: &str, password: &str) -> Socks5Proxy {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+}
+
+
+impl Tunnel for Socks5Proxy {
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn tunnel(&self, target: &str, protocol: Box<dyn Protocol>) -> io::Result<Box<dyn Protocol>> {
+        if let Some((ref username, ref password)) = self.credentials {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Credentials too long"));
+            }
+        }
+        let handshake = Socks5Handshake {
+            target: Target::parse(target)?,
+            credentials: self.credentials.clone(),
+            state: ClientState::Greeting,
+        };
+        Ok(Box::new(TunnelClient::new("SOCKS server", target, handshake, protocol)))
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClientState {
+    Greeting,
+    Auth,
+    Request,
+}
+
+
+/// Send the request to the SOCKS server, authenticated if it asks to,
+/// the tunnel is established once the server connected to the target.
+struct Socks5Handshake {
+    target: Target,
+    credentials: Option<(String, String)>,
+    state: ClientState,
+}
+
+
+impl Socks5Handshake {
+    fn send_request(&mut self, transport: &mut Transport) {
+        let mut request = vec![VERSION, CONNECT, 0];
+        request.extend_from_slice(&self.target.encode());
+        transport.write(&request);
+        self.state = ClientState::Request;
+    }
+}
+
+
+impl Handshake for Socks5Handshake {
+    fn start(&mut self, transport: &mut Transport) {
+        let method = if self.credentials.is_some() { USER_PASSWORD } else { NO_AUTH };
+        transport.write(&[VERSION, 1, method]);
+    }
+
+    /// Read the reply of the current state.
+    fn read(&mut self, data: &[u8], transport: &mut Transport) -> Result<Reply, String> {
+        match self.state {
+            ClientState::Greeting if data.len() >= 2 => {
+                match (data[0], data[1], self.credentials.clone()) {
+                    (VERSION, NO_AUTH, _) => self.send_request(transport),
+                    (VERSION, USER_PASSWORD, Some((username, password))) => {
+                        let mut auth = vec![AUTH_VERSION, username.len() as u8];
+                        auth.extend_from_slice(username.as_bytes());
+                        auth.push(password.len() as u8);
+                        auth.extend_from_slice(password.as_bytes());
+                        transport.write(&auth);
+                        self.state = ClientState::Auth;
+                    }
+                    _ => return Err("no acceptable authentication method".to_string()),
+                }
+                Ok(Reply::Read(2))
+            }
+            ClientState::Auth if data.len() >= 2 => {
+                if data[1] != 0 {
+                    return Err("authentication failed".to_string());
+                }
+                self.send_request(transport);
+                Ok(Reply::Read(2))
+            }
+            ClientState::Request if data.len() >= 4 => {
+                let len = match Target::decode(&data[3..]) {
+                    Ok(Some((_, len))) => 3 + len,
+                    Ok(None) => return Ok(Reply::Partial),
+                    Err(_) => return Err("invalid reply".to_string()),
+                };
+                if data[0] != VERSION || data[1] != SUCCEEDED {
+                    return Err(format!("request failed with the code {}", data[1]));
+                }
+                Ok(Reply::Established(len))
+            }
+            _ => Ok(Reply::Partial),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use interface::{Protocol, ServerFactory, Tunnel};
+    use testing::ProtocolHarness;
+
+    use super::{error_code, Socks5Factory, Socks5Proxy, Target};
+
+    #[test]
+    pub fn test_target() {
+        for target in &["127.0.0.1:80", "[::1]:443", "example.com:8080"] {
+            let parsed = Target::parse(target).unwrap();
+            let encoded = parsed.encode();
+            assert_eq!(Target::decode(&encoded[..]), Ok(Some((parsed, encoded.len()))));
+            assert_eq!(Target::decode(&encoded[..encoded.len() - 1]), Ok(None));
+        }
+        assert!(Target::parse("example.com").is_err());
+        assert!(Target::parse(":80").is_err());
+        assert_eq!(Target::decode(&[9, 0, 0]), Err(8));
+    }
+
+    #[test]
+    pub fn test_server_auth() {
+        let factory = Socks5Factory::new().user("alice", "secret");
+        let mut harness = ProtocolHarness::new(factory.build_protocol());
+        harness.connect();
+        // no authentication is refused
+        harness.feed(&[5, 1, 0]);
+        assert_eq!(harness.written(), &[5, 0xff]);
+        assert!(!harness.is_connected());
+
+        let mut harness = ProtocolHarness::new(factory.build_protocol());
+        harness.connect();
+        harness.feed(&[5, 2, 0, 2]);
+        assert_eq!(harness.take_written(), &[5, 2]);
+        harness.feed(b"\x01\x05alice\x05wrong");
+        assert_eq!(harness.written(), &[1, 1]);
+        assert!(!harness.is_connected());
+    }
+
+    #[test]
+    pub fn test_server_command() {
+        let mut harness = ProtocolHarness::new(Socks5Factory::new().build_protocol());
+        harness.connect();
+        // BIND, in many reads
+        harness.feed(&[5, 1]);
+        harness.feed(&[0, 5, 2, 0]);
+        assert_eq!(harness.take_written(), &[5, 0]);
+        harness.feed(&[1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(harness.written(), &[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(!harness.is_connected());
+    }
+
+    #[test]
+    pub fn test_server_connect() {
+        let mut harness = ProtocolHarness::new(Socks5Factory::new().build_protocol());
+        harness.connect();
+        harness.feed(&[5, 1, 0]);
+        assert_eq!(harness.take_written(), &[5, 0]);
+        // nothing is replied or read until the target is connected
+        harness.feed(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        assert_eq!(harness.written(), b"");
+        assert!(harness.reading_paused());
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(error_code(&refused), 5);
+        assert_eq!(error_code(&io::Error::from(io::ErrorKind::TimedOut)), 1);
+    }
+
+    #[test]
+    pub fn test_server_resolving() {
+        let mut harness = ProtocolHarness::new(Socks5Factory::new().build_protocol());
+        harness.connect();
+        harness.feed(&[5, 1, 0]);
+        assert_eq!(harness.take_written(), &[5, 0]);
+        // the name is resolved in the executor, not run by the harness
+        harness.feed(b"\x05\x01\x00\x03\x09localhost\x00\x50");
+        harness.wakeup();
+        assert_eq!(harness.written(), b"");
+        assert!(harness.reading_paused());
+        assert!(harness.is_connected());
+    }
+
+    #[test]
+    pub fn test_client() {
+        let proxy = Socks5Proxy::new("127.0.0.1:1080").unwrap().credentials("alice", "secret");
+        let client = proxy.tunnel("example.com:80", Box::new(NoopProtocol)).unwrap();
+        let mut harness = ProtocolHarness::new(client);
+        harness.connect();
+        assert_eq!(harness.take_written(), &[5, 1, 2]);
+        harness.feed(&[5, 2]);
+        assert_eq!(harness.take_written(), b"\x01\x05alice\x06secret");
+        harness.feed(&[1, 0]);
+        assert_eq!(harness.take_written(), b"\x05\x01\x00\x03\x0bexample.com\x00\x50");
+        harness.feed(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(!harness.is_connected());
+    }
+
+    struct NoopProtocol;
+
+    impl Protocol for NoopProtocol {}
+}
@@ -0,0 +1,379 @@
+//! Use the connections with the futures: a `ByteStream` is a `Stream` of
+//! the data received, and a `Sink` of the data to write.
+//!
+//! The streams are connected with `ByteStream::connect`, or accepted by a
+//! server listening with a `StreamFactory`. The futures using them are
+//! spawned on the loop with `Rio::spawn_future`.
+//!
+//! The sink is not ready while the transport has too much data to write,
+//! and the connection is not read while too much data received is not
+//! taken from the stream. Dropping the stream hangs up the connection.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::task::{self, Task};
+use mio::Token;
+
+use interface::{Protocol, Reason, ServerFactory};
+use rio::{Rio, Waker};
+use transport::Transport;
+
+
+/// The length of the data received and not taken from the stream above
+/// which the connection is not read anymore, until it is under the low
+/// watermark. The sink is not ready either while the data sent to it
+/// and not passed to the transport yet is above the high watermark.
+const HIGH_WATERMARK: usize = 64 * 1024;
+const LOW_WATERMARK: usize = 16 * 1024;
+
+
+#[derive(Default)]
+struct Shared {
+    token: Option<Token>,
+    received: VecDeque<Vec<u8>>,
+    received_len: usize,
+    outgoing: Vec<u8>,
+    // the writing side is shut down once the outgoing data is written
+    closing: bool,
+    shut: bool,
+    paused: bool,
+    reading_paused: bool,
+    // the stream is dropped, the connection is hung up
+    dropped: bool,
+    eof: bool,
+    lost: Option<Reason>,
+    reader: Option<Task>,
+    writer: Option<Task>,
+}
+
+
+impl Shared {
+    fn notify_reader(&mut self) {
+        if let Some(task) = self.reader.take() {
+            task.notify();
+        }
+    }
+
+    fn notify_writer(&mut self) {
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+    }
+}
+
+
+fn broken_pipe() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The connection is closed")
+}
+
+
+/// A connection of the loop, as a `Stream` of the chunks of data received
+/// and a `Sink` of the data to write.
+pub struct ByteStream {
+    shared: Rc<RefCell<Shared>>,
+    waker: Waker,
+}
+
+
+impl ByteStream {
+    /// A stream not connected yet, its protocol is connected or accepted
+    /// by the loop.
+    pub fn new(rio: &Rio) -> ByteStream {
+        ByteStream {
+            shared: Rc::new(RefCell::new(Shared::default())),
+            waker: rio.waker(),
+        }
+    }
+
+    /// Connect a new stream to the address.
+    pub fn connect(rio: &mut Rio, addr: &str) -> io::Result<ByteStream> {
+        let stream = ByteStream::new(rio);
+        rio.connect(addr, stream.protocol())?;
+        Ok(stream)
+    }
+
+    /// The protocol of the connection, it is made once.
+    pub fn protocol(&self) -> Box<dyn Protocol> {
+        Box::new(StreamProtocol {
+            shared: self.shared.clone(),
+            accepted: None,
+        })
+    }
+
+    /// The token of the connection, once made.
+    pub fn token(&self) -> Option<Token> {
+        self.shared.borrow().token
+    }
+
+    fn wake(&self) {
+        if let Some(token) = self.shared.borrow().token {
+            self.waker.wake(token);
+        }
+    }
+}
+
+
+impl Stream for ByteStream {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if let Some(data) = shared.received.pop_front() {
+            shared.received_len -= data.len();
+            if shared.reading_paused && shared.received_len < LOW_WATERMARK {
+                drop(shared);
+                // the reading is resumed by the protocol
+                self.wake();
+            }
+            return Ok(Async::Ready(Some(data)));
+        }
+        if shared.eof {
+            return Ok(Async::Ready(None));
+        }
+        match shared.lost {
+            Some(Reason::HangUp) => Ok(Async::Ready(None)),
+            Some(_) => Err(io::Error::new(io::ErrorKind::ConnectionReset, "The connection is lost")),
+            None => {
+                shared.reader = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+
+impl Sink for ByteStream {
+    type SinkItem = Vec<u8>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, data: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+        {
+            let mut shared = self.shared.borrow_mut();
+            if shared.lost.is_some() || shared.closing {
+                return Err(broken_pipe());
+            }
+            if shared.paused || shared.outgoing.len() >= HIGH_WATERMARK {
+                shared.writer = Some(task::current());
+                return Ok(AsyncSink::NotReady(data));
+            }
+            shared.outgoing.extend_from_slice(&data);
+        }
+        self.wake();
+        Ok(AsyncSink::Ready)
+    }
+
+    /// Ready once the data is written to the transport.
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        let mut shared = self.shared.borrow_mut();
+        if shared.outgoing.is_empty() && shared.shut == shared.closing {
+            return Ok(Async::Ready(()));
+        }
+        if shared.lost.is_some() {
+            return Err(broken_pipe());
+        }
+        shared.writer = Some(task::current());
+        Ok(Async::NotReady)
+    }
+
+    /// Shut down the writing side of the connection, the stream still
+    /// receives the data of the peer.
+    fn close(&mut self) -> Poll<(), io::Error> {
+        let closing = {
+            let mut shared = self.shared.borrow_mut();
+            let closing = !shared.closing && shared.lost.is_none();
+            shared.closing = true;
+            closing
+        };
+        if closing {
+            self.wake();
+        }
+        self.poll_complete()
+    }
+}
+
+
+impl Drop for ByteStream {
+    fn drop(&mut self) {
+        let lost = {
+            let mut shared = self.shared.borrow_mut();
+            shared.dropped = true;
+            shared.lost.is_some()
+        };
+        if !lost {
+            self.wake();
+        }
+    }
+}
+
+
+/// The connections accepted by a `StreamFactory`.
+#[derive(Default)]
+struct Accepted {
+    streams: VecDeque<ByteStream>,
+    task: Option<Task>,
+}
+
+
+/// Accept the connections of a server as streams, received from
+/// the `incoming` stream.
+pub struct StreamFactory {
+    accepted: Rc<RefCell<Accepted>>,
+    waker: Waker,
+}
+
+
+impl StreamFactory {
+    pub fn new(rio: &Rio) -> StreamFactory {
+        StreamFactory {
+            accepted: Rc::new(RefCell::new(Accepted::default())),
+            waker: rio.waker(),
+        }
+    }
+
+    /// The stream of the accepted connections, it never ends.
+    pub fn incoming(&self) -> Incoming {
+        Incoming { accepted: self.accepted.clone() }
+    }
+}
+
+
+impl ServerFactory for StreamFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        let stream = ByteStream {
+            shared: Rc::new(RefCell::new(Shared::default())),
+            waker: self.waker.clone(),
+        };
+        Box::new(StreamProtocol {
+            shared: stream.shared.clone(),
+            accepted: Some((stream, self.accepted.clone())),
+        })
+    }
+}
+
+
+/// The stream of the connections accepted by a `StreamFactory`.
+pub struct Incoming {
+    accepted: Rc<RefCell<Accepted>>,
+}
+
+
+impl Stream for Incoming {
+    type Item = ByteStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<ByteStream>, io::Error> {
+        let mut accepted = self.accepted.borrow_mut();
+        match accepted.streams.pop_front() {
+            Some(stream) => Ok(Async::Ready(Some(stream))),
+            None => {
+                accepted.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+
+/// Move the data between the connection and its stream.
+struct StreamProtocol {
+    shared: Rc<RefCell<Shared>>,
+    // the stream is accepted once the connection is made
+    accepted: Option<(ByteStream, Rc<RefCell<Accepted>>)>,
+}
+
+
+impl StreamProtocol {
+    fn flush(&mut self, transport: &mut Transport) {
+        let mut shared = self.shared.borrow_mut();
+        if shared.lost.is_some() {
+            return;
+        }
+        if shared.dropped {
+            debug!("Stream of {:?} dropped, hanging up", transport.token());
+            if !shared.outgoing.is_empty() {
+                transport.write(&shared.outgoing);
+                shared.outgoing.clear();
+            }
+            transport.hang_up();
+            return;
+        }
+        if shared.reading_paused && shared.received_len < LOW_WATERMARK {
+            transport.resume_reading();
+            shared.reading_paused = false;
+        }
+        if shared.paused {
+            return;
+        }
+        if !shared.outgoing.is_empty() {
+            transport.write(&shared.outgoing);
+            shared.outgoing.clear();
+        }
+        if shared.closing && !shared.shut {
+            transport.write_eof();
+            shared.shut = true;
+        }
+        shared.notify_writer();
+    }
+}
+
+
+impl Protocol for StreamProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        self.shared.borrow_mut().token = Some(transport.token());
+        if let Some((stream, accepted)) = self.accepted.take() {
+            let mut accepted = accepted.borrow_mut();
+            accepted.streams.push_back(stream);
+            if let Some(task) = accepted.task.take() {
+                task.notify();
+            }
+        }
+        self.flush(transport);
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        {
+            let mut shared = self.shared.borrow_mut();
+            shared.received.push_back(data.to_vec());
+            shared.received_len += data.len();
+            if !shared.reading_paused && shared.received_len > HIGH_WATERMARK {
+                transport.pause_reading();
+                shared.reading_paused = true;
+            }
+            shared.notify_reader();
+        }
+        self.flush(transport);
+    }
+
+    fn eof_received(&mut self, _transport: &mut Transport) -> bool {
+        let mut shared = self.shared.borrow_mut();
+        shared.eof = true;
+        shared.notify_reader();
+        // keep the connection open while the sink is not closed
+        !shared.shut && !shared.dropped
+    }
+
+    fn pause_writing(&mut self, _transport: &mut Transport) {
+        self.shared.borrow_mut().paused = true;
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        self.shared.borrow_mut().paused = false;
+        self.flush(transport);
+    }
+
+    fn wakeup(&mut self, transport: &mut Transport) {
+        self.flush(transport);
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        let mut shared = self.shared.borrow_mut();
+        shared.lost = Some(reason);
+        shared.notify_reader();
+        shared.notify_writer();
+    }
+}
@@ -0,0 +1,378 @@
+//! Utilities to test protocols.
+//!
+//! The `ProtocolHarness` drives a `Protocol` with a transport that is
+//! not connected, the bytes written by the protocol are collected
+//! to be asserted.
+//!
+//! The `Loopback` runs a server and its clients on the same loop,
+//! using the loopback interface.
+//!
+//! The `ManualClock` is a clock for the loop that is advanced by the
+//! tests, to call the timers without waiting.
+//!
+//! The `EchoFactory` builds an `EchoProtocol` for every connection,
+//! a server writing back the data it receives.
+
+use std::cell::Cell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+
+use clock::Clock;
+use interface::{self, Protocol, ServerFactory, Reason};
+use rio::Rio;
+use transport::Transport;
+
+
+/// Drive a protocol like the loop does for a connection.
+pub struct ProtocolHarness {
+    protocol: Box<dyn Protocol>,
+    transport: Transport,
+    written: Vec<u8>,
+    connected: bool,
+    reason: Option<Reason>,
+}
+
+
+impl ProtocolHarness {
+    /// Wrap the protocol, call `connect` to start the connection.
+    pub fn new(protocol: Box<dyn Protocol>) -> ProtocolHarness {
+        ProtocolHarness {
+            protocol,
+            transport: Transport::new(),
+            written: Vec::new(),
+            connected: false,
+            reason: None,
+        }
+    }
+
+    /// Call the `connection_made` method of the protocol.
+    pub fn connect(&mut self) {
+        assert!(!self.connected && self.reason.is_none(), "Connection already made");
+        self.connected = true;
+        self.protocol.connection_made(&mut self.transport);
+        self.flush();
+    }
+
+    /// Call the `data_received` method of the protocol, as if the
+    /// peer sent the data. If the protocol provides a buffer with
+    /// `get_buffer`, the data are copied in it and `buffer_updated`
+    /// is called instead.
+    pub fn feed(&mut self, data: &[u8]) {
+        assert!(self.is_connected(), "Cannot feed a closed connection");
+        interface::deliver(&mut *self.protocol, data, &mut self.transport);
+        self.flush();
+    }
+
+    /// Call the `wakeup` method of the protocol, as if the connection
+    /// was woken up by `Rio::wake`.
+    pub fn wakeup(&mut self) {
+        assert!(self.is_connected(), "Cannot wake up a closed connection");
+        self.protocol.wakeup(&mut self.transport);
+        self.flush();
+    }
+
+    /// Close the connection as if the peer closed it, the `connection_lost`
+    /// method of the protocol is called with `Reason::ConnectionLost`.
+    pub fn peer_hang_up(&mut self) {
+        self.close(Reason::ConnectionLost);
+    }
+
+    /// Close the connection as if it failed, the `connection_lost`
+    /// method of the protocol is called with `Reason::ConnectionError`.
+    pub fn fail(&mut self) {
+        self.close(Reason::ConnectionError);
+    }
+
+    /// Call the `connection_failed` method of the protocol instead of
+    /// `connect`, as if the connect failed with the error.
+    pub fn connection_failed(&mut self, error: &io::Error) {
+        assert!(!self.connected && self.reason.is_none(), "Connection already made");
+        self.reason = Some(Reason::ConnectionError);
+        self.protocol.connection_failed(error, &mut self.transport);
+        self.written.extend_from_slice(&self.transport.buf()[..]);
+        self.transport.clear();
+    }
+
+    /// True once the protocol paused the reading of the connection,
+    /// until it resumes it.
+    pub fn reading_paused(&self) -> bool {
+        self.transport.reading_paused()
+    }
+
+    /// The bytes written by the protocol since the last call to
+    /// `take_written`.
+    pub fn written(&self) -> &[u8] {
+        &self.written[..]
+    }
+
+    /// Return the bytes written by the protocol, and forget them.
+    pub fn take_written(&mut self) -> Vec<u8> {
+        self.written.split_off(0)
+    }
+
+    /// True until the connection is closed by the protocol or the peer.
+    pub fn is_connected(&self) -> bool {
+        self.connected && self.reason.is_none()
+    }
+
+    /// The reason passed to `connection_lost` once the connection is closed,
+    /// `Reason::ConnectionError` once the connect failed.
+    pub fn reason(&self) -> Option<Reason> {
+        self.reason
+    }
+
+    fn flush(&mut self) {
+        self.written.extend_from_slice(&self.transport.buf()[..]);
+        self.transport.clear();
+        if self.transport.hup() {
+            self.close(Reason::HangUp);
+        }
+    }
+
+    fn close(&mut self, reason: Reason) {
+        assert!(self.is_connected(), "Connection already closed");
+        self.reason = Some(reason);
+        self.protocol.connection_lost(reason);
+    }
+}
+
+
+/// A server listening on an ephemeral port of the loopback interface,
+/// and the loop to connect clients to it.
+pub struct Loopback {
+    rio: Rio,
+    server: Token,
+    addr: SocketAddr,
+}
+
+
+impl Loopback {
+    /// Listen on an ephemeral port of `127.0.0.1` with the factory.
+    pub fn new(server: Box<dyn ServerFactory>) -> io::Result<Loopback> {
+        let mut rio = Rio::new();
+        let listener = rio.listen("127.0.0.1:0", server)?;
+        Ok(Loopback { rio, server: listener.token(), addr: listener.local_addr() })
+    }
+
+    /// The address the server is bound to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The token of the server.
+    pub fn server(&self) -> Token {
+        self.server
+    }
+
+    /// The loop running the server.
+    pub fn rio(&mut self) -> &mut Rio {
+        &mut self.rio
+    }
+
+    /// Connect a client protocol to the server.
+    pub fn connect(&mut self, client: Box<dyn Protocol>) -> io::Result<Token> {
+        let addr = self.addr.to_string();
+        self.rio.connect(&addr, client)
+    }
+
+    /// Run the loop until the predicate holds or the timeout is reached,
+    /// return the last result of the predicate.
+    pub fn run_until<F>(&mut self, timeout: Duration, predicate: F) -> bool
+        where F: Fn(&Rio) -> bool
+    {
+        let deadline = Instant::now() + timeout;
+        if predicate(&self.rio) {
+            return true;
+        }
+        self.rio.run_until(&|rio: &Rio| -> bool { !predicate(rio) && Instant::now() < deadline });
+        predicate(&self.rio)
+    }
+}
+
+
+/// A clock that only moves when advanced, shared between the test
+/// and the loop by cloning it. The loop polls without waiting with it.
+#[derive(Clone)]
+pub struct ManualClock {
+    origin: Instant,
+    elapsed: Rc<Cell<Duration>>,
+}
+
+
+impl ManualClock {
+    /// A clock stopped at the current time.
+    pub fn new() -> ManualClock {
+        ManualClock {
+            origin: Instant::now(),
+            elapsed: Rc::new(Cell::new(Duration::from_millis(0))),
+        }
+    }
+
+    /// Move the time forward, the timers are called on the next
+    /// iteration of the loop.
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+
+    /// The time elapsed since the clock has been created.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+}
+
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed.get()
+    }
+
+    fn is_virtual(&self) -> bool {
+        true
+    }
+}
+
+
+/// Write back the data received.
+pub struct EchoProtocol;
+
+
+impl Protocol for EchoProtocol {
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        transport.write(data);
+    }
+}
+
+
+/// Build an `EchoProtocol` for every connection.
+pub struct EchoFactory;
+
+
+impl ServerFactory for EchoFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(EchoProtocol)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+    use std::str;
+
+    use interface::{Protocol, Reason};
+    use transport::Transport;
+    use super::ProtocolHarness;
+
+    struct EchoProtocol;
+
+    impl Protocol for EchoProtocol {
+        fn connection_made(&mut self, transport: &mut Transport) {
+            transport.write(b"Hello\n");
+        }
+
+        fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+            transport.write(data);
+            if str::from_utf8(data).unwrap().trim() == "bye" {
+                transport.hang_up();
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_protocol_hang_up() {
+        let mut harness = ProtocolHarness::new(Box::new(EchoProtocol));
+        harness.connect();
+        assert_eq!(harness.take_written(), b"Hello\n");
+
+        harness.feed(b"hello\n");
+        assert_eq!(harness.written(), b"hello\n");
+        harness.feed(b"bye\n");
+        assert_eq!(harness.written(), b"hello\nbye\n");
+        assert!(!harness.is_connected());
+        assert_eq!(harness.reason(), Some(Reason::HangUp));
+    }
+
+    #[test]
+    pub fn test_peer_hang_up() {
+        let mut harness = ProtocolHarness::new(Box::new(EchoProtocol));
+        harness.connect();
+        harness.peer_hang_up();
+        assert!(!harness.is_connected());
+        assert_eq!(harness.reason(), Some(Reason::ConnectionLost));
+    }
+
+    /// Receive fixed size records in its own buffer.
+    struct RecordProtocol {
+        record: [u8; 4],
+        len: usize,
+    }
+
+    impl Protocol for RecordProtocol {
+        fn get_buffer(&mut self, _: usize) -> Option<&mut [u8]> {
+            Some(&mut self.record[self.len..])
+        }
+
+        fn buffer_updated(&mut self, nbytes: usize, transport: &mut Transport) {
+            self.len += nbytes;
+            if self.len == self.record.len() {
+                transport.write(b"ok\n");
+                self.len = 0;
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_buffered_protocol() {
+        let mut harness = ProtocolHarness::new(Box::new(RecordProtocol {
+            record: [0; 4],
+            len: 0,
+        }));
+        harness.connect();
+        harness.feed(b"abcdef");
+        assert_eq!(harness.take_written(), b"ok\n");
+        harness.feed(b"gh");
+        assert_eq!(harness.take_written(), b"ok\n");
+    }
+
+    #[test]
+    pub fn test_fail() {
+        let mut harness = ProtocolHarness::new(Box::new(EchoProtocol));
+        harness.connect();
+        harness.fail();
+        assert_eq!(harness.reason(), Some(Reason::ConnectionError));
+    }
+
+    /// Record the error of the connect.
+    struct ConnectProtocol {
+        error: Rc<Cell<Option<io::ErrorKind>>>,
+    }
+
+    impl Protocol for ConnectProtocol {
+        fn connection_failed(&mut self, error: &io::Error, _: &mut Transport) {
+            self.error.set(Some(error.kind()));
+        }
+    }
+
+    #[test]
+    pub fn test_connection_failed() {
+        let error = Rc::new(Cell::new(None));
+        let mut harness = ProtocolHarness::new(Box::new(ConnectProtocol { error: error.clone() }));
+        harness.connection_failed(&io::Error::from(io::ErrorKind::ConnectionRefused));
+        assert_eq!(error.get(), Some(io::ErrorKind::ConnectionRefused));
+        assert!(!harness.is_connected());
+        assert_eq!(harness.reason(), Some(Reason::ConnectionError));
+    }
+}
@@ -1,10 +1,132 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::Token;
+use nix::libc::pid_t;
+use nix::sys::signal::{self, Signal};
+
+use interface::Protocol;
+use options::{self, Keepalive};
+use pool::Job;
+use proxy_header::{ProxyHeader, ProxyVersion};
+
+
+const HIGH_WATERMARK: usize = 64 * 1024;
+const LOW_WATERMARK: usize = 16 * 1024;
+
+
+/// A buffer or a file region waiting to be written to the socket.
+enum Chunk {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+    File { file: File, offset: u64, len: usize },
+}
+
+impl Chunk {
+    fn as_slice(&self) -> Option<&[u8]> {
+        match *self {
+            Chunk::Owned(ref data) => Some(&data[..]),
+            Chunk::Shared(ref data) => Some(&data[..]),
+            Chunk::File { .. } => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Chunk::Owned(ref data) => data.len(),
+            Chunk::Shared(ref data) => data.len(),
+            Chunk::File { len, .. } => len,
+        }
+    }
+}
+
+
+/// An operation on another connection of the loop, done once the
+/// protocol method returns.
+#[doc(hidden)]
+pub enum Action {
+    Write(Token, Vec<u8>),
+    WriteEof(Token),
+    HangUp(Token),
+    Wake(Token),
+    Join(Token, String),
+    Leave(Token, String),
+    Publish(String, Arc<[u8]>),
+    PauseReading(Token),
+    ResumeReading(Token),
+    Connect(SocketAddr, Box<dyn Protocol>, Option<Vec<u8>>),
+    RunInExecutor(Job),
+}
 
 
 /// Transport is a proxy for the socket write access.
 /// Instance are passed as arguments of the trait `Protocol` event method.
 pub struct Transport {
-    buf: Vec<u8>,
+    chunks: VecDeque<Chunk>,
+    // bytes of the first buffer already written
+    offset: usize,
     hup: bool,
+    eof: bool,
+    reading_paused: bool,
+    writing_paused: bool,
+    high_watermark: usize,
+    low_watermark: usize,
+    socket: Option<RawFd>,
+    // the source address of the PROXY protocol header
+    proxied_addr: Option<SocketAddr>,
+    token: Token,
+    linked: Option<Token>,
+    actions: Vec<Action>,
+}
+
+
+/// A proxy for another connection of the loop, returned by
+/// `Transport::peer`.
+pub struct Peer<'a> {
+    token: Token,
+    actions: &'a mut Vec<Action>,
+}
+
+
+impl<'a> Peer<'a> {
+    /// Will write the data to the connection.
+    pub fn write(&mut self, data: &[u8]) {
+        self.actions.push(Action::Write(self.token, data.to_vec()));
+    }
+
+    /// Will shut down the writing side of the connection, once
+    /// the pending data has been written.
+    pub fn write_eof(&mut self) {
+        self.actions.push(Action::WriteEof(self.token));
+    }
+
+    /// Will close the connection.
+    pub fn hang_up(&mut self) {
+        self.actions.push(Action::HangUp(self.token));
+    }
+
+    /// Stop reading the connection.
+    pub fn pause_reading(&mut self) {
+        self.actions.push(Action::PauseReading(self.token));
+    }
+
+    /// Will call the `wakeup` method of the protocol of the connection.
+    pub fn wake(&mut self) {
+        self.actions.push(Action::Wake(self.token));
+    }
+
+    /// Read the connection again after `pause_reading`.
+    pub fn resume_reading(&mut self) {
+        self.actions.push(Action::ResumeReading(self.token));
+    }
 }
 
 
@@ -12,14 +134,79 @@ impl Transport {
     #[doc(hidden)]
     pub fn new() -> Transport {
         Transport {
-            buf: Vec::new(),
+            chunks: VecDeque::new(),
+            offset: 0,
             hup: false,
+            eof: false,
+            reading_paused: false,
+            writing_paused: false,
+            high_watermark: HIGH_WATERMARK,
+            low_watermark: LOW_WATERMARK,
+            socket: None,
+            proxied_addr: None,
+            token: Token(0),
+            linked: None,
+            actions: Vec::new(),
+        }
+    }
+
+    #[doc(hidden)]
+    pub fn with_socket(socket: RawFd) -> Transport {
+        Transport {
+            chunks: VecDeque::new(),
+            offset: 0,
+            hup: false,
+            eof: false,
+            reading_paused: false,
+            writing_paused: false,
+            high_watermark: HIGH_WATERMARK,
+            low_watermark: LOW_WATERMARK,
+            socket: Some(socket),
+            proxied_addr: None,
+            token: Token(0),
+            linked: None,
+            actions: Vec::new(),
         }
     }
 
     /// Will write the data to connected socket
     pub fn write(&mut self, data: &[u8]) {
-        self.buf.extend(data.iter());
+        if data.is_empty() {
+            return;
+        }
+        if let Some(&mut Chunk::Owned(ref mut buf)) = self.chunks.back_mut() {
+            buf.extend_from_slice(data);
+            return;
+        }
+        self.chunks.push_back(Chunk::Owned(data.to_vec()));
+    }
+
+    /// Will write the data to connected socket, without copying it.
+    pub fn write_owned(&mut self, data: Vec<u8>) {
+        if !data.is_empty() {
+            self.chunks.push_back(Chunk::Owned(data));
+        }
+    }
+
+    /// Will write the data to connected socket, without copying it,
+    /// the same data can be written to many transports.
+    pub fn write_shared(&mut self, data: Arc<[u8]>) {
+        if !data.is_empty() {
+            self.chunks.push_back(Chunk::Shared(data));
+        }
+    }
+
+    /// Will write `len` bytes of the file from the `offset`, using
+    /// `sendfile`, after the data already written.
+    pub fn send_file(&mut self, file: File, offset: u64, len: usize) {
+        if len > 0 {
+            self.chunks.push_back(Chunk::File { file, offset, len });
+        }
+    }
+
+    /// The number of bytes waiting to be written.
+    pub fn pending(&self) -> usize {
+        self.chunks.iter().map(Chunk::len).sum::<usize>() - self.offset
     }
 
     /// Will close the connection
@@ -28,6 +215,175 @@ impl Transport {
         self.hup = true;
     }
 
+    /// The file descriptor of the socket, to read the socket options,
+    /// or set the ones not covered by the transport.
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.socket
+    }
+
+    /// Will shut down the writing side of the connection once the
+    /// pending data has been written, the peer can still send data.
+    pub fn write_eof(&mut self) {
+        debug!("Shutting down the writing side of {:?}", self.token);
+        self.eof = true;
+    }
+
+    /// Stop reading the connection, the peer is slowed down once
+    /// the kernel buffers are full.
+    pub fn pause_reading(&mut self) {
+        self.reading_paused = true;
+    }
+
+    /// Read the connection again after `pause_reading`.
+    pub fn resume_reading(&mut self) {
+        self.reading_paused = false;
+    }
+
+    /// The `Protocol.pause_writing` method is called once more than
+    /// `high` bytes are waiting to be written, and the
+    /// `Protocol.resume_writing` method once they are `low` or less.
+    pub fn set_write_buffer_limits(&mut self, high: usize, low: usize) {
+        self.high_watermark = high;
+        self.low_watermark = cmp::min(low, high);
+    }
+
+    /// The token of the connection in the loop.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The address of the connected peer, or the source address of the
+    /// PROXY protocol header if the listener reads it.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self.proxied_addr {
+            Some(addr) => Ok(addr),
+            None => self.with_stream(|socket| socket.peer_addr()),
+        }
+    }
+
+    /// The local address of the socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.with_stream(|socket| socket.local_addr())
+    }
+
+    fn with_stream<F, T>(&self, f: F) -> io::Result<T>
+        where F: FnOnce(&TcpStream) -> io::Result<T>
+    {
+        match self.socket {
+            Some(fd) => {
+                let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+                f(&socket)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "No socket")),
+        }
+    }
+
+    /// Will connect to the address, the new connection is linked to
+    /// this one: when one is closed, the other is hung up once its
+    /// pending data has been written.
+    pub fn connect(&mut self, addr: SocketAddr, protocol: Box<dyn Protocol>) {
+        self.actions.push(Action::Connect(addr, protocol, None));
+    }
+
+    /// Will connect to the address like `connect`, and write the PROXY
+    /// protocol header with the addresses of this connection first.
+    pub fn connect_proxied(&mut self, addr: SocketAddr, protocol: Box<dyn Protocol>, version: ProxyVersion) {
+        let header = match (self.peer_addr(), self.local_addr()) {
+            (Ok(source), Ok(destination)) => ProxyHeader::new(source, destination),
+            _ => ProxyHeader::Local,
+        };
+        self.actions.push(Action::Connect(addr, protocol, Some(header.encode(version))));
+    }
+
+    /// The token of the linked connection, opened by `connect`
+    /// or that opened this one, until it is closed.
+    pub fn linked(&self) -> Option<Token> {
+        self.linked
+    }
+
+    /// A proxy to another connection of the loop, such as the linked one.
+    /// Its methods take effect once the protocol method returns,
+    /// and are ignored if the connection is closed.
+    pub fn peer(&mut self, token: Token) -> Peer<'_> {
+        Peer {
+            token,
+            actions: &mut self.actions,
+        }
+    }
+
+    /// Will add the connection to the channel of the loop, to receive
+    /// the data published on it.
+    pub fn join(&mut self, channel: &str) {
+        self.actions.push(Action::Join(self.token, channel.to_string()));
+    }
+
+    /// Will remove the connection from the channel. The connections
+    /// leave their channels once closed.
+    pub fn leave(&mut self, channel: &str) {
+        self.actions.push(Action::Leave(self.token, channel.to_string()));
+    }
+
+    /// Will write the data to every member of the channel, including
+    /// this connection if it joined it. The data is not copied.
+    pub fn publish(&mut self, channel: &str, data: &[u8]) {
+        self.actions.push(Action::Publish(channel.to_string(), Arc::from(data)));
+    }
+
+    /// Will run the work on a thread of the executor of the loop, see
+    /// `Rio::run_in_executor`. The protocol is woken up once the callback
+    /// is called with the result, on the loop.
+    pub fn run_in_executor<W, T, C>(&mut self, work: W, callback: C)
+        where W: FnOnce() -> T + Send + 'static,
+              T: Send + 'static,
+              C: FnOnce(T) + 'static
+    {
+        self.actions.push(Action::RunInExecutor(Job::new(work, callback)));
+    }
+
+    /// Set `TCP_NODELAY` on the socket, to disable the Nagle algorithm.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_nodelay(fd, nodelay))
+    }
+
+    /// Set `SO_KEEPALIVE` on the socket, `None` to disable it.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_keepalive(fd, keepalive.as_ref()))
+    }
+
+    /// Set `SO_LINGER` on the socket, `None` to close it in background.
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_linger(fd, linger))
+    }
+
+    /// Set `IP_TTL` on the socket.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_ttl(fd, ttl))
+    }
+
+    /// Set `SO_RCVBUF` on the socket.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_recv_buffer_size(fd, size))
+    }
+
+    /// Set `SO_SNDBUF` on the socket.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.setsockopt(|fd| options::set_send_buffer_size(fd, size))
+    }
+
+    // Socket options are ignored by a transport without socket,
+    // such as the one used to test protocols.
+    fn setsockopt<F>(&self, setter: F) -> io::Result<()>
+        where F: FnOnce(RawFd) -> io::Result<()>
+    {
+        match self.socket {
+            Some(fd) => setter(fd),
+            None => {
+                debug!("No socket to set the option");
+                Ok(())
+            }
+        }
+    }
+
     // Not the public api.
 
     #[doc(hidden)]
@@ -36,18 +392,196 @@ impl Transport {
     }
 
     #[doc(hidden)]
-    pub fn buf(&self) -> &Vec<u8> {
-        &self.buf
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+
+    #[doc(hidden)]
+    pub fn reading_paused(&self) -> bool {
+        self.reading_paused
+    }
+
+    #[doc(hidden)]
+    pub fn set_proxied_addr(&mut self, addr: Option<SocketAddr>) {
+        self.proxied_addr = addr;
+    }
+
+    #[doc(hidden)]
+    pub fn set_token(&mut self, token: Token) {
+        self.token = token;
+    }
+
+    #[doc(hidden)]
+    pub fn set_linked(&mut self, linked: Option<Token>) {
+        self.linked = linked;
+    }
+
+    #[doc(hidden)]
+    pub fn take_actions(&mut self) -> Vec<Action> {
+        self.actions.split_off(0)
+    }
+
+    /// Return true if `Protocol.pause_writing` has to be called, false
+    /// for `Protocol.resume_writing`, according to the pending data.
+    #[doc(hidden)]
+    pub fn update_writing(&mut self) -> Option<bool> {
+        let pending = self.pending();
+        if !self.writing_paused && pending > self.high_watermark {
+            self.writing_paused = true;
+            Some(true)
+        } else if self.writing_paused && pending <= self.low_watermark {
+            self.writing_paused = false;
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// The bytes waiting to be written, files are read.
+    #[doc(hidden)]
+    pub fn buf(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.pending());
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            match *chunk {
+                Chunk::File { ref file, offset, len } => {
+                    let start = buf.len();
+                    buf.resize(start + len, 0);
+                    if let Err(err) = file.read_exact_at(&mut buf[start..], offset) {
+                        error!("Cannot read the file to send: {}", err);
+                        buf.truncate(start);
+                    }
+                }
+                _ => {
+                    let offset = if idx == 0 { self.offset } else { 0 };
+                    buf.extend_from_slice(&chunk.as_slice().unwrap()[offset..]);
+                }
+            }
+        }
+        buf
+    }
+
+    /// The buffers waiting to be written before the next file,
+    /// in at most `max` slices.
+    #[doc(hidden)]
+    pub fn slices(&self, max: usize) -> Vec<&[u8]> {
+        self.chunks.iter()
+            .take(max)
+            .map_while(Chunk::as_slice)
+            .enumerate()
+            .map(|(idx, slice)| if idx == 0 { &slice[self.offset..] } else { slice })
+            .collect()
+    }
+
+    /// The file region to send, if it is the next chunk to write.
+    #[doc(hidden)]
+    pub fn file(&self) -> Option<(&File, u64, usize)> {
+        match self.chunks.front() {
+            Some(&Chunk::File { ref file, offset, len }) => Some((file, offset, len)),
+            _ => None,
+        }
+    }
+
+    /// Forget the bytes that have been written.
+    #[doc(hidden)]
+    pub fn consume(&mut self, len: usize) {
+        let mut len = len;
+        while len > 0 {
+            let remaining = match self.chunks.front_mut() {
+                Some(&mut Chunk::File { ref mut offset, len: ref mut file_len, .. }) if len < *file_len => {
+                    *offset += len as u64;
+                    *file_len -= len;
+                    break;
+                }
+                Some(chunk) => chunk.len() - self.offset,
+                None => break,
+            };
+            if len < remaining {
+                self.offset += len;
+                break;
+            }
+            len -= remaining;
+            self.offset = 0;
+            self.chunks.pop_front();
+        }
     }
 
     #[doc(hidden)]
     pub fn should_write(&self) -> bool {
-        !self.buf.is_empty()
+        !self.chunks.is_empty()
     }
 
     #[doc(hidden)]
     pub fn clear(&mut self) {
-        self.buf.clear();
+        self.chunks.clear();
+        self.offset = 0;
+    }
+}
+
+
+/// SubprocessTransport is a proxy for a spawned process.
+/// Instance are passed as arguments of the trait `SubprocessProtocol`
+/// event method.
+pub struct SubprocessTransport {
+    pid: pid_t,
+    buf: Vec<u8>,
+    close_stdin: bool,
+}
+
+
+impl SubprocessTransport {
+    #[doc(hidden)]
+    pub fn new(pid: u32) -> SubprocessTransport {
+        SubprocessTransport {
+            pid: pid as pid_t,
+            buf: Vec::new(),
+            close_stdin: false,
+        }
+    }
+
+    /// The process id of the child process
+    pub fn pid(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// Will write the data to the standard input of the process
+    pub fn write(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Will close the standard input of the process once
+    /// the pending data has been written.
+    pub fn close_stdin(&mut self) {
+        debug!("Closing stdin of process {}", self.pid);
+        self.close_stdin = true;
+    }
+
+    /// Send a signal to the process
+    pub fn send_signal(&self, signal: Signal) -> io::Result<()> {
+        info!("Sending {:?} to process {}", signal, self.pid);
+        signal::kill(self.pid, signal)?;
+        Ok(())
+    }
+
+    /// Kill the process using SIGKILL
+    pub fn kill(&self) -> io::Result<()> {
+        self.send_signal(Signal::SIGKILL)
+    }
+
+    // Not the public api.
+
+    #[doc(hidden)]
+    pub fn buf(&self) -> &Vec<u8> {
+        &self.buf
+    }
+
+    #[doc(hidden)]
+    pub fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+    }
+
+    #[doc(hidden)]
+    pub fn should_close_stdin(&self) -> bool {
+        self.close_stdin && self.buf.is_empty()
     }
 }
 
@@ -56,7 +590,13 @@ impl Transport {
 
 #[cfg(test)]
 mod test {
-    use super::Transport;
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+    use std::process;
+    use std::sync::Arc;
+
+    use super::{Transport, SubprocessTransport};
 
     #[test]
     pub fn test_transport() {
@@ -75,7 +615,81 @@ mod test {
         assert!(!&transport.hup());
         transport.hang_up();
         assert!(&transport.hup());
+    }
+
+    #[test]
+    pub fn test_options_without_socket() {
+        let transport = Transport::new();
+        assert!(transport.raw_fd().is_none());
+        // no socket to set options on
+        assert!(transport.set_nodelay(true).is_ok());
+    }
+
+    #[test]
+    pub fn test_chunks() {
+        let shared: Arc<[u8]> = Arc::from(&b"shared"[..]);
+        let mut transport = Transport::new();
+        transport.write(b"tele");
+        transport.write(b"port ");
+        transport.write_shared(shared.clone());
+        transport.write_owned(b" owned".to_vec());
+        transport.write(b"!");
+        assert_eq!(transport.slices(8), vec![&b"teleport "[..], &b"shared"[..], &b" owned!"[..]]);
+        assert_eq!(transport.pending(), 22);
+
+        transport.consume(4);
+        assert_eq!(transport.slices(1), vec![&b"port "[..]]);
+        transport.consume(7);
+        assert_eq!(transport.buf(), b"ared owned!");
+        transport.consume(11);
+        assert!(!transport.should_write());
+        assert_eq!(transport.pending(), 0);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    pub fn test_send_file() {
+        let mut file = tempfile("janeiro-test-send-file");
+        file.write_all(b"0123456789").unwrap();
+
+        let mut transport = Transport::new();
+        transport.write(b"head ");
+        transport.send_file(file, 2, 6);
+        transport.write(b" tail");
+        assert_eq!(transport.slices(8), vec![&b"head "[..]]);
+        assert_eq!(transport.pending(), 16);
+        assert_eq!(transport.buf(), b"head 234567 tail");
+
+        transport.consume(5);
+        assert_eq!(transport.slices(8), Vec::<&[u8]>::new());
+        assert_eq!(transport.file().map(|(_, offset, len)| (offset, len)), Some((2, 6)));
+        transport.consume(4);
+        assert_eq!(transport.file().map(|(_, offset, len)| (offset, len)), Some((6, 2)));
+        transport.consume(2);
+        assert!(transport.file().is_none());
+        assert_eq!(transport.buf(), b" tail");
+    }
+
+    fn tempfile(name: &str) -> File {
+        let path = env::temp_dir().join(format!("{}-{}", name, process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[test]
+    pub fn test_subprocess_transport() {
+        let mut transport = SubprocessTransport::new(42);
+        assert_eq!(transport.pid(), 42);
+        transport.write(b"tele");
+        transport.write(b"port");
+        transport.close_stdin();
+        assert!(!transport.should_close_stdin());
 
+        transport.consume(4);
+        assert_eq!(&transport.buf()[..], b"port");
+        transport.consume(4);
+        assert!(transport.should_close_stdin());
     }
 
 }
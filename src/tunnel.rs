@@ -0,0 +1,239 @@
+//! The client side of a tunnel through a proxy server: the handshake
+//! with the server, then the protocol made as if it was directly
+//! connected to the target.
+
+use interface::{self, Protocol, Reason};
+use transport::Transport;
+
+
+/// What the handshake read of the replies of the proxy server.
+pub enum Reply {
+    /// The reply is not fully received yet.
+    Partial,
+    /// A reply of that length is read, the handshake goes on.
+    Read(usize),
+    /// The last reply of that length is read, the tunnel is established.
+    Established(usize),
+}
+
+
+/// The requests of a client to a proxy server, to open a tunnel.
+pub trait Handshake {
+    /// Send the first request, once connected to the proxy server.
+    fn start(&mut self, transport: &mut Transport);
+
+    /// Read the reply at the start of the data and send the next request,
+    /// or return the message of the error if the server refused.
+    fn read(&mut self, data: &[u8], transport: &mut Transport) -> Result<Reply, String>;
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Handshake,
+    Connected,
+    Failed,
+}
+
+
+/// Run the handshake, then make the protocol once the tunnel is
+/// established and pass it everything. If the handshake fails, the
+/// `connection_lost` method of the protocol is called with
+/// `Reason::ConnectionError`.
+pub struct TunnelClient<H> {
+    server: &'static str,
+    target: String,
+    handshake: H,
+    state: State,
+    buf: Vec<u8>,
+    protocol: Box<dyn Protocol>,
+}
+
+
+impl<H: Handshake> TunnelClient<H> {
+    /// The `server` names the kind of proxy server in the logs.
+    pub fn new(server: &'static str, target: &str, handshake: H, protocol: Box<dyn Protocol>) -> TunnelClient<H> {
+        TunnelClient {
+            server,
+            target: target.to_string(),
+            handshake,
+            state: State::Handshake,
+            buf: Vec::new(),
+            protocol,
+        }
+    }
+
+    fn fail(&mut self, transport: &mut Transport, message: &str) {
+        error!("Cannot connect to {} through the {}: {}", self.target, self.server, message);
+        transport.hang_up();
+        self.state = State::Failed;
+        self.protocol.connection_lost(Reason::ConnectionError);
+    }
+
+    /// Read the replies received, until the tunnel is established.
+    fn read_replies(&mut self, transport: &mut Transport) {
+        loop {
+            match self.handshake.read(&self.buf[..], transport) {
+                Ok(Reply::Partial) => break,
+                Ok(Reply::Read(len)) => {
+                    self.buf.drain(..len);
+                }
+                Ok(Reply::Established(len)) => {
+                    debug!("Connected to {} through the {}", self.target, self.server);
+                    self.state = State::Connected;
+                    let data = self.buf.split_off(len);
+                    self.buf = Vec::new();
+                    self.protocol.connection_made(transport);
+                    interface::deliver(&mut *self.protocol, &data[..], transport);
+                    break;
+                }
+                Err(message) => {
+                    self.fail(transport, &message);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+
+impl<H: Handshake> Protocol for TunnelClient<H> {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        self.handshake.start(transport);
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        match self.state {
+            State::Connected => self.protocol.data_received(data, transport),
+            State::Failed => {}
+            State::Handshake => {
+                self.buf.extend_from_slice(data);
+                self.read_replies(transport);
+            }
+        }
+    }
+
+    fn get_buffer(&mut self, size_hint: usize) -> Option<&mut [u8]> {
+        match self.state {
+            State::Connected => self.protocol.get_buffer(size_hint),
+            _ => None,
+        }
+    }
+
+    fn buffer_updated(&mut self, nbytes: usize, transport: &mut Transport) {
+        if self.state == State::Connected {
+            self.protocol.buffer_updated(nbytes, transport);
+        }
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        match self.state {
+            State::Connected => self.protocol.eof_received(transport),
+            _ => false,
+        }
+    }
+
+    fn pause_writing(&mut self, transport: &mut Transport) {
+        if self.state == State::Connected {
+            self.protocol.pause_writing(transport);
+        }
+    }
+
+    fn resume_writing(&mut self, transport: &mut Transport) {
+        if self.state == State::Connected {
+            self.protocol.resume_writing(transport);
+        }
+    }
+
+    fn wakeup(&mut self, transport: &mut Transport) {
+        if self.state == State::Connected {
+            self.protocol.wakeup(transport);
+        }
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        match self.state {
+            State::Connected => self.protocol.connection_lost(reason),
+            State::Failed => {}
+            State::Handshake => self.protocol.connection_lost(Reason::ConnectionError),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use interface::Protocol;
+    use testing::ProtocolHarness;
+    use transport::Transport;
+
+    use super::{Handshake, Reply, TunnelClient};
+
+    /// Established once the server replied `ok`.
+    struct OkHandshake;
+
+    impl Handshake for OkHandshake {
+        fn start(&mut self, transport: &mut Transport) {
+            transport.write(b"open\n");
+        }
+
+        fn read(&mut self, data: &[u8], _: &mut Transport) -> Result<Reply, String> {
+            match data.iter().position(|&byte| byte == b'\n') {
+                Some(pos) if &data[..pos] == b"ok" => Ok(Reply::Established(pos + 1)),
+                Some(_) => Err("refused".to_string()),
+                None => Ok(Reply::Partial),
+            }
+        }
+    }
+
+    /// Receive the data in its own buffer.
+    struct BufferedProtocol {
+        buf: [u8; 4],
+        received: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Protocol for BufferedProtocol {
+        fn get_buffer(&mut self, _: usize) -> Option<&mut [u8]> {
+            Some(&mut self.buf)
+        }
+
+        fn buffer_updated(&mut self, nbytes: usize, _: &mut Transport) {
+            self.received.borrow_mut().extend_from_slice(&self.buf[..nbytes]);
+        }
+    }
+
+    #[test]
+    pub fn test_tunnel_client() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let protocol = BufferedProtocol { buf: [0; 4], received: received.clone() };
+        let mut client = TunnelClient::new("test server", "example.com:80", OkHandshake, Box::new(protocol));
+        let mut transport = Transport::new();
+        // nothing is passed to the protocol before the tunnel is established
+        assert!(client.get_buffer(4).is_none());
+        client.buffer_updated(4, &mut transport);
+        assert!(received.borrow().is_empty());
+
+        let mut harness = ProtocolHarness::new(Box::new(client));
+        harness.connect();
+        assert_eq!(harness.take_written(), b"open\n");
+        harness.feed(b"o");
+        harness.feed(b"k\nhello");
+        assert_eq!(*received.borrow(), b"hello");
+        assert!(harness.is_connected());
+    }
+
+    #[test]
+    pub fn test_tunnel_refused() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let protocol = BufferedProtocol { buf: [0; 4], received: received.clone() };
+        let client = TunnelClient::new("test server", "example.com:80", OkHandshake, Box::new(protocol));
+        let mut harness = ProtocolHarness::new(Box::new(client));
+        harness.connect();
+        harness.feed(b"no\nhello");
+        assert!(!harness.is_connected());
+        assert!(received.borrow().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+extern crate janeiro;
+extern crate net2;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use net2::TcpBuilder;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol};
+use janeiro::balancer::{Balancer, HealthCheck, Strategy};
+
+
+/// Reply with the name of the backend, then hang up.
+struct NameProtocol {
+    name: &'static str,
+}
+
+impl Protocol for NameProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(self.name.as_bytes());
+        transport.hang_up();
+    }
+}
+
+
+struct NameFactory {
+    name: &'static str,
+}
+
+impl ServerFactory for NameFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(NameProtocol { name: self.name })
+    }
+}
+
+
+fn request(addr: SocketAddr) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut received = String::new();
+        let _ = stream.read_to_string(&mut received);
+        received
+    })
+}
+
+
+fn fetch(rio: &mut Rio, addr: SocketAddr) -> String {
+    let client = request(addr);
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    client.join().unwrap()
+}
+
+
+fn backends(rio: &mut Rio) -> Vec<SocketAddr> {
+    ["a", "b"].iter()
+        .map(|name| rio.listen("127.0.0.1:0", Box::new(NameFactory { name })).unwrap().local_addr())
+        .collect()
+}
+
+
+#[test]
+fn test_round_robin() {
+    let mut rio = Rio::new();
+    let backends = backends(&mut rio);
+    let balancer = Balancer::new(&backends, Strategy::RoundRobin);
+    let front = rio.listen("127.0.0.1:0", Box::new(balancer.factory())).unwrap().local_addr();
+
+    let names = (0..4).map(|_| fetch(&mut rio, front)).collect::<Vec<String>>();
+    assert_eq!(names, vec!["a", "b", "a", "b"]);
+}
+
+
+#[test]
+fn test_consistent_hash() {
+    let mut rio = Rio::new();
+    let backends = backends(&mut rio);
+    let balancer = Balancer::new(&backends, Strategy::ConsistentHash);
+    let front = rio.listen("127.0.0.1:0", Box::new(balancer.factory())).unwrap().local_addr();
+
+    let first = fetch(&mut rio, front);
+    for _ in 0..3 {
+        assert_eq!(fetch(&mut rio, front), first);
+    }
+}
+
+
+#[test]
+fn test_health_checks() {
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down_addr = down.local_addr().unwrap();
+    drop(down);
+
+    let mut rio = Rio::new();
+    let mut backends = backends(&mut rio);
+    backends.insert(0, down_addr);
+    let balancer = Balancer::new(&backends, Strategy::RoundRobin);
+    let front = rio.listen("127.0.0.1:0", Box::new(balancer.factory())).unwrap().local_addr();
+    balancer.health_checks(&mut rio, HealthCheck::new(Duration::from_millis(10)).fall(2).rise(2));
+
+    rio.run_until(&|_: &Rio| -> bool { balancer.healthy().len() == 3 });
+    assert_eq!(balancer.healthy(), &backends[1..]);
+    let names = (0..4).map(|_| fetch(&mut rio, front)).collect::<Vec<String>>();
+    assert_eq!(names, vec!["a", "b", "a", "b"]);
+    assert_eq!(balancer.connections(down_addr), 0);
+
+    // the backend is admitted again once it is back
+    rio.listen(&down_addr.to_string(), Box::new(NameFactory { name: "c" })).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { balancer.healthy().len() < 3 });
+    let mut names = (0..3).map(|_| fetch(&mut rio, front)).collect::<Vec<String>>();
+    names.sort();
+    assert_eq!(names, vec!["a", "b", "c"]);
+}
+
+
+#[test]
+fn test_health_check_timeout() {
+    // the backlog of the backend is full, the connections to it
+    // never complete, as to a non-routable address
+    let backend = TcpBuilder::new_v4().unwrap().bind("127.0.0.1:0").unwrap().listen(0).unwrap();
+    let addr = backend.local_addr().unwrap();
+    let _queued = TcpStream::connect(addr).unwrap();
+
+    let mut rio = Rio::new();
+    let balancer = Balancer::new(&[addr], Strategy::RoundRobin);
+    balancer.health_checks(&mut rio, HealthCheck::new(Duration::from_millis(20)).timeout(Duration::from_millis(10)));
+
+    let mut max_count = 0;
+    let deadline = Instant::now() + Duration::from_millis(300);
+    while Instant::now() < deadline {
+        rio.run_once();
+        max_count = max_count.max(rio.connections_count());
+    }
+    assert!(balancer.healthy().is_empty());
+    // the probes timed out are closed
+    assert_eq!(max_count, 1);
+}
+
+
+#[test]
+fn test_stop_health_checks() {
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down_addr = down.local_addr().unwrap();
+    drop(down);
+
+    let mut rio = Rio::new();
+    let balancer = Balancer::new(&[down_addr], Strategy::RoundRobin);
+    let check = HealthCheck::new(Duration::from_millis(10)).fall(1).rise(1);
+    // started again, the checks replace the ones running
+    balancer.health_checks(&mut rio, check.clone());
+    balancer.health_checks(&mut rio, check);
+    rio.run_until(&|_: &Rio| -> bool { !balancer.healthy().is_empty() });
+    assert!(balancer.stop_health_checks(&mut rio));
+    assert!(!balancer.stop_health_checks(&mut rio));
+
+    // the backend is back, but not checked anymore
+    rio.listen(&down_addr.to_string(), Box::new(NameFactory { name: "a" })).unwrap();
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while Instant::now() < deadline {
+        rio.run_once();
+    }
+    assert!(balancer.healthy().is_empty());
+}
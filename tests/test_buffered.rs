@@ -0,0 +1,69 @@
+extern crate janeiro;
+
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::rc::Rc;
+
+use janeiro::{Rio, RioBuilder, Transport, ServerFactory, Protocol};
+
+
+/// Receive the bytes in a preallocated buffer, and reply with its
+/// size once full.
+struct PreallocatedProtocol {
+    buf: Vec<u8>,
+    len: usize,
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Protocol for PreallocatedProtocol {
+    fn data_received(&mut self, _: &[u8], _: &mut Transport) {
+        panic!("The bytes must be received in the buffer of the protocol");
+    }
+
+    fn get_buffer(&mut self, _: usize) -> Option<&mut [u8]> {
+        Some(&mut self.buf[self.len..])
+    }
+
+    fn buffer_updated(&mut self, nbytes: usize, transport: &mut Transport) {
+        self.len += nbytes;
+        if self.len == self.buf.len() {
+            *self.received.borrow_mut() = self.buf.clone();
+            transport.write(format!("{}\n", self.len).as_bytes());
+            transport.hang_up();
+        }
+    }
+}
+
+
+struct PreallocatedFactory {
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl ServerFactory for PreallocatedFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(PreallocatedProtocol {
+            buf: vec![0; 10000],
+            len: 0,
+            received: self.received.clone(),
+        })
+    }
+}
+
+
+#[test]
+fn test_buffered_protocol() {
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut rio = RioBuilder::new().read_buffer_size(16).build().unwrap();
+    let listener = rio.listen("127.0.0.1:0", Box::new(PreallocatedFactory { received: received.clone() })).unwrap();
+
+    let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let mut client = TcpStream::connect(listener.local_addr()).unwrap();
+    client.write_all(&data[..]).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { received.borrow().is_empty() });
+
+    assert_eq!(*received.borrow(), data);
+    let mut reply = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "10000\n");
+}
@@ -0,0 +1,65 @@
+extern crate janeiro;
+
+use std::cell::RefCell;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use janeiro::{Rio, RioBuilder, Transport, ServerFactory, Protocol};
+
+
+struct CollectProtocol {
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Protocol for CollectProtocol {
+    fn data_received(&mut self, data: &[u8], _: &mut Transport) {
+        self.received.borrow_mut().extend_from_slice(data);
+    }
+}
+
+
+struct CollectFactory {
+    received: Rc<RefCell<Vec<u8>>>,
+}
+
+impl ServerFactory for CollectFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(CollectProtocol { received: self.received.clone() })
+    }
+}
+
+
+#[test]
+fn test_small_read_buffer() {
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut rio = RioBuilder::new()
+        .connections_capacity(4)
+        .events_capacity(2)
+        .read_buffer_size(3)
+        .build()
+        .unwrap();
+    let listener = rio.listen("127.0.0.1:0", Box::new(CollectFactory { received: received.clone() })).unwrap();
+
+    let mut client = TcpStream::connect(listener.local_addr()).unwrap();
+    client.write_all(b"hello world\n").unwrap();
+    rio.run_until(&|_: &Rio| -> bool { received.borrow().len() < 12 });
+    assert_eq!(&received.borrow()[..], b"hello world\n");
+}
+
+
+#[test]
+fn test_poll_timeout() {
+    let mut rio = RioBuilder::new().poll_timeout(Duration::from_millis(10)).build().unwrap();
+    let start = Instant::now();
+    rio.run_once();
+    assert!(start.elapsed() < Duration::from_millis(400));
+}
+
+
+#[test]
+fn test_invalid_builder() {
+    assert!(RioBuilder::new().read_buffer_size(0).build().is_err());
+    assert!(RioBuilder::new().events_capacity(0).build().is_err());
+}
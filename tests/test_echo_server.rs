@@ -4,7 +4,6 @@ extern crate janeiro;
 extern crate log;
 extern crate env_logger;
 
-use std::str;
 use std::thread;
 use std::process::Command;
 use std::time::Duration;
@@ -61,4 +60,5 @@ fn test_echo_server() {
     // todo tests that the stream has been closed by peer here
 
     child.kill().unwrap();
+    let _ = child.wait();
 }
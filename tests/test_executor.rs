@@ -0,0 +1,127 @@
+extern crate janeiro;
+extern crate mio;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use mio::Token;
+
+use janeiro::{Rio, RioBuilder, Transport, ServerFactory, Protocol};
+
+
+/// Reply the line in upper case, computed slowly outside of the loop.
+struct SlowProtocol {
+    replies: Rc<RefCell<VecDeque<String>>>,
+    completed: Rc<Cell<usize>>,
+    done: Arc<AtomicBool>,
+}
+
+impl Protocol for SlowProtocol {
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        let line = String::from_utf8_lossy(data).into_owned();
+        let replies = self.replies.clone();
+        let completed = self.completed.clone();
+        let done = self.done.clone();
+        transport.run_in_executor(move || {
+            thread::sleep(Duration::from_millis(200));
+            done.store(true, Ordering::SeqCst);
+            line.to_uppercase()
+        }, move |reply| {
+            completed.set(completed.get() + 1);
+            replies.borrow_mut().push_back(reply);
+        });
+    }
+
+    fn wakeup(&mut self, transport: &mut Transport) {
+        while let Some(reply) = self.replies.borrow_mut().pop_front() {
+            transport.write(reply.as_bytes());
+        }
+    }
+}
+
+
+struct SlowFactory {
+    completed: Rc<Cell<usize>>,
+    done: Arc<AtomicBool>,
+}
+
+impl ServerFactory for SlowFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(SlowProtocol {
+            replies: Rc::new(RefCell::new(VecDeque::new())),
+            completed: self.completed.clone(),
+            done: self.done.clone(),
+        })
+    }
+}
+
+
+fn slow_factory() -> (SlowFactory, Rc<Cell<usize>>, Arc<AtomicBool>) {
+    let completed = Rc::new(Cell::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let factory = SlowFactory {
+        completed: completed.clone(),
+        done: done.clone(),
+    };
+    (factory, completed, done)
+}
+
+
+#[test]
+fn test_run_in_executor() {
+    let mut rio = Rio::new();
+    let (factory, completed, _) = slow_factory();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+    assert!(rio.run_in_executor(Token(42), || (), |_| ()).is_err());
+
+    let clients = ["hello\n", "world\n"].iter().map(|line| {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(line.as_bytes()).unwrap();
+            let mut reply = vec![0; line.len()];
+            stream.read_exact(&mut reply).unwrap();
+            String::from_utf8(reply).unwrap()
+        })
+    }).collect::<Vec<_>>();
+
+    // the loop is not blocked while the work runs
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let timer_events = events.clone();
+    rio.call_later(Duration::from_millis(20), Box::new(move |_: &mut Rio| timer_events.borrow_mut().push("timer")));
+    rio.run_until(&|_: &Rio| -> bool {
+        if completed.get() > 0 && events.borrow().is_empty() {
+            events.borrow_mut().push("completed");
+        }
+        completed.get() < 2
+    });
+    assert_eq!(events.borrow()[0], "timer");
+
+    let replies = clients.into_iter().map(|client| client.join().unwrap()).collect::<Vec<_>>();
+    assert_eq!(replies, vec!["HELLO\n", "WORLD\n"]);
+}
+
+
+#[test]
+fn test_closed_connection() {
+    let mut rio = RioBuilder::new().poll_timeout(Duration::from_millis(10)).executor_threads(1).build().unwrap();
+    let (factory, completed, done) = slow_factory();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"hello\n").unwrap();
+    stream.shutdown(Shutdown::Both).unwrap();
+    rio.run_until(&|rio: &Rio| -> bool { !done.load(Ordering::SeqCst) || rio.connections_count() > 0 });
+
+    // the result is dropped, the connection is closed
+    for _ in 0..10 {
+        rio.run_once();
+    }
+    assert_eq!(completed.get(), 0);
+}
@@ -0,0 +1,200 @@
+extern crate futures;
+extern crate janeiro;
+
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::{stream, Future, Sink, Stream};
+use futures::sync::oneshot;
+
+use janeiro::{Rio, RioBuilder};
+use janeiro::stream::{ByteStream, StreamFactory};
+use janeiro::testing::EchoFactory;
+
+
+#[test]
+fn test_spawn_future() {
+    let mut rio = Rio::new();
+    let (sender, receiver) = oneshot::channel::<u32>();
+    let received = Rc::new(Cell::new(None));
+    let result = received.clone();
+    let token = rio.spawn_future(receiver.map(move |value| result.set(Some(value))).map_err(|_| ())).unwrap();
+    assert!(rio.contains(token));
+
+    // notified from another thread
+    let sending = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        sender.send(42).unwrap();
+    });
+    rio.run_until(&|_: &Rio| -> bool { received.get().is_none() });
+    sending.join().unwrap();
+    assert_eq!(received.get(), Some(42));
+    assert!(!rio.contains(token));
+}
+
+
+#[test]
+fn test_stream_server() {
+    let mut rio = Rio::new();
+    let factory = StreamFactory::new(&rio);
+    let incoming = factory.incoming();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+
+    let served = Rc::new(Cell::new(0));
+    let counter = served.clone();
+    let echo = incoming.take(1).for_each(move |stream| {
+        let counter = counter.clone();
+        let (sink, stream) = stream.split();
+        sink.send_all(stream).map(move |_| counter.set(counter.get() + 1))
+    });
+    rio.spawn_future(echo.map_err(|err| panic!("Cannot echo: {}", err))).unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    rio.run_until(&|_: &Rio| -> bool { served.get() == 0 });
+    assert_eq!(client.join().unwrap(), b"hello");
+}
+
+
+#[test]
+fn test_stream_client() {
+    let mut rio = Rio::new();
+    let addr = rio.listen("127.0.0.1:0", Box::new(EchoFactory)).unwrap().local_addr();
+    let stream = ByteStream::connect(&mut rio, &addr.to_string()).unwrap();
+
+    let received = Rc::new(RefCell::new(None));
+    let result = received.clone();
+    let echo = stream.send(b"hello".to_vec())
+        .and_then(|stream| stream.into_future().map_err(|(err, _)| err))
+        .map(move |(data, _)| *result.borrow_mut() = data)
+        .map_err(|err| panic!("Cannot receive the echo: {}", err));
+    rio.spawn_future(echo).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { received.borrow().is_none() });
+    assert_eq!(*received.borrow(), Some(b"hello".to_vec()));
+}
+
+
+/// Run the loop for the duration.
+fn run_for(rio: &mut Rio, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    rio.run_until(&|_: &Rio| -> bool { Instant::now() < deadline });
+}
+
+
+#[test]
+fn test_drop_stream() {
+    let mut rio = RioBuilder::new().poll_timeout(Duration::from_millis(10)).build().unwrap();
+    let factory = StreamFactory::new(&rio);
+    let incoming = factory.incoming();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+    rio.spawn_future(incoming.for_each(|stream| {
+        drop(stream);
+        Ok(())
+    }).map_err(|err| panic!("Cannot accept: {}", err))).unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).map(|_| received)
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    assert!(client.join().unwrap().unwrap().is_empty());
+    assert_eq!(rio.connections_count(), 0);
+}
+
+
+#[test]
+fn test_read_backpressure() {
+    let mut rio = RioBuilder::new().poll_timeout(Duration::from_millis(10)).build().unwrap();
+    let factory = StreamFactory::new(&rio);
+    let incoming = factory.incoming();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+    let accepted = Rc::new(RefCell::new(None));
+    let stream = accepted.clone();
+    rio.spawn_future(incoming.take(1).for_each(move |accepted| {
+        *stream.borrow_mut() = Some(accepted);
+        Ok(())
+    }).map_err(|err| panic!("Cannot accept: {}", err))).unwrap();
+
+    // the writes block once the stream stops reading the connection
+    let total = 64 * 1024 * 1024;
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+        let chunk = vec![42; 64 * 1024];
+        let mut written = 0;
+        while written < total {
+            match stream.write(&chunk[..]) {
+                Ok(len) => written += len,
+                Err(_) => break,
+            }
+        }
+        written
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    let written = client.join().unwrap();
+    assert!(written < total, "{} bytes written", written);
+
+    // everything is received once the stream is read
+    let received = Rc::new(Cell::new(0));
+    let counter = received.clone();
+    let stream = accepted.borrow_mut().take().unwrap();
+    rio.spawn_future(stream.for_each(move |data| {
+        counter.set(counter.get() + data.len());
+        Ok(())
+    }).map_err(|err| panic!("Cannot read: {}", err))).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { received.get() < written });
+    assert_eq!(received.get(), written);
+}
+
+
+#[test]
+fn test_write_backpressure() {
+    let mut rio = RioBuilder::new().poll_timeout(Duration::from_millis(10)).build().unwrap();
+    let factory = StreamFactory::new(&rio);
+    let incoming = factory.incoming();
+    let addr = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+
+    // the chunks are taken from the source while the sink is ready
+    let chunks = 1024;
+    let produced = Rc::new(Cell::new(0));
+    let counter = produced.clone();
+    let sent = Rc::new(Cell::new(false));
+    let done = sent.clone();
+    rio.spawn_future(incoming.take(1).for_each(move |accepted| {
+        let counter = counter.clone();
+        let done = done.clone();
+        let source = stream::iter_ok::<_, io::Error>((0..chunks).map(move |_| {
+            counter.set(counter.get() + 1);
+            vec![42; 64 * 1024]
+        }));
+        // the connection is hung up once the stream is dropped
+        accepted.send_all(source).map(move |_| done.set(true))
+    }).map_err(|err| panic!("Cannot write: {}", err))).unwrap();
+
+    let stream = TcpStream::connect(addr).unwrap();
+    run_for(&mut rio, Duration::from_millis(300));
+    assert!(produced.get() < chunks, "{} chunks produced", produced.get());
+
+    let client = thread::spawn(move || {
+        let mut stream = stream;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received.len()
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    assert!(sent.get());
+    assert_eq!(client.join().unwrap(), chunks * 64 * 1024);
+}
@@ -0,0 +1,61 @@
+extern crate janeiro;
+
+use std::cell::Cell;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol};
+
+
+struct IdleProtocol {
+    connected: Rc<Cell<bool>>,
+}
+
+impl Protocol for IdleProtocol {
+    fn connection_made(&mut self, _: &mut Transport) {
+        self.connected.set(true);
+    }
+}
+
+
+struct IdleFactory {
+    connected: Rc<Cell<bool>>,
+}
+
+impl ServerFactory for IdleFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(IdleProtocol { connected: self.connected.clone() })
+    }
+}
+
+
+#[test]
+fn test_handover() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.as_raw_fd();
+
+    let connected = Rc::new(Cell::new(false));
+    let mut rio = Rio::new();
+    let token = rio.listen_fd(listener.into_raw_fd(),
+                              Box::new(IdleFactory { connected: connected.clone() })).unwrap().token();
+
+    let client = TcpStream::connect(addr).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { !connected.get() });
+
+    let mut command = Command::new("sh");
+    command.arg("-c")
+        .arg("test -e /proc/self/fd/$JANEIRO_LISTEN_FDS && echo $JANEIRO_LISTEN_FDS")
+        .stdout(Stdio::piped());
+    let child = rio.handover(command).unwrap();
+    assert!(!rio.contains(token));
+
+    // the loop stops once the established connection is closed.
+    drop(client);
+    rio.run_forever();
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}\n", fd));
+}
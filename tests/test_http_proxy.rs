@@ -0,0 +1,134 @@
+extern crate janeiro;
+
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Reason};
+use janeiro::http_proxy::HttpProxy;
+use janeiro::proxy::ProxyProtocol;
+use janeiro::testing::EchoFactory;
+
+
+/// A minimal HTTP proxy, for the `CONNECT` method only.
+struct ConnectProtocol {
+    authorization: Option<&'static str>,
+    request: Vec<u8>,
+    proxy: Option<ProxyProtocol>,
+}
+
+impl Protocol for ConnectProtocol {
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        if let Some(ref mut proxy) = self.proxy {
+            return proxy.data_received(data, transport);
+        }
+        self.request.extend_from_slice(data);
+        let len = match self.request.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => return,
+        };
+        let request = str::from_utf8(&self.request[..len]).unwrap().to_string();
+        let target = request.split(' ').nth(1).unwrap();
+        let authorized = match self.authorization {
+            Some(authorization) => request.contains(&format!("Proxy-Authorization: {}\r\n", authorization)),
+            None => true,
+        };
+        if !authorized {
+            transport.write(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+            transport.hang_up();
+            return;
+        }
+        let addr: SocketAddr = FromStr::from_str(target).unwrap();
+        let early_data = self.request.split_off(len);
+        transport.connect(addr, Box::new(ProxyProtocol::backend_side(early_data)));
+        transport.write(b"HTTP/1.1 200 Connection established\r\n\r\n");
+        self.proxy = Some(ProxyProtocol::backend_side(Vec::new()));
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        match self.proxy {
+            Some(ref mut proxy) => proxy.eof_received(transport),
+            None => false,
+        }
+    }
+}
+
+
+struct ConnectFactory {
+    authorization: Option<&'static str>,
+}
+
+impl ServerFactory for ConnectFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(ConnectProtocol {
+            authorization: self.authorization,
+            request: Vec::new(),
+            proxy: None,
+        })
+    }
+}
+
+
+/// Send a line once connected, and hang up once it is echoed.
+struct LineProtocol {
+    received: Rc<RefCell<Vec<u8>>>,
+    reason: Rc<Cell<Option<Reason>>>,
+}
+
+impl Protocol for LineProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"hello\n");
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.received.borrow_mut().extend_from_slice(data);
+        if self.received.borrow().ends_with(b"\n") {
+            transport.hang_up();
+        }
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        self.reason.set(Some(reason));
+    }
+}
+
+
+fn echo_through(authorization: Option<&'static str>, proxy: &dyn Fn(SocketAddr) -> HttpProxy) -> (Vec<u8>, Option<Reason>) {
+    let mut rio = Rio::new();
+    let echo = rio.listen("127.0.0.1:0", Box::new(EchoFactory)).unwrap().local_addr();
+    let http_proxy = rio.listen("127.0.0.1:0", Box::new(ConnectFactory { authorization })).unwrap().local_addr();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let reason = Rc::new(Cell::new(None));
+    let client = LineProtocol {
+        received: received.clone(),
+        reason: reason.clone(),
+    };
+    rio.connect_through(&proxy(http_proxy), &echo.to_string(), Box::new(client)).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { reason.get().is_none() });
+    let received = received.borrow().clone();
+    (received, reason.get())
+}
+
+
+#[test]
+fn test_connect() {
+    let (received, reason) = echo_through(None, &|addr| HttpProxy::new(&addr.to_string()).unwrap());
+    assert_eq!(received, b"hello\n");
+    assert_eq!(reason, Some(Reason::HangUp));
+}
+
+
+#[test]
+fn test_basic_auth() {
+    let authorization = Some("Basic YWxpY2U6c2VjcmV0");
+    let (received, reason) = echo_through(authorization,
+                                          &|addr| HttpProxy::new(&addr.to_string()).unwrap().basic_auth("alice", "secret"));
+    assert_eq!(received, b"hello\n");
+    assert_eq!(reason, Some(Reason::HangUp));
+
+    let (received, reason) = echo_through(authorization, &|addr| HttpProxy::new(&addr.to_string()).unwrap());
+    assert_eq!(received, b"");
+    assert_eq!(reason, Some(Reason::ConnectionError));
+}
@@ -0,0 +1,113 @@
+extern crate janeiro;
+extern crate net2;
+
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use net2::TcpStreamExt;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol};
+
+
+/// Publish every line received to the room, leave it on `leave`.
+struct ChatProtocol;
+
+impl Protocol for ChatProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.join("room");
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        if data == b"leave\n" {
+            transport.leave("room");
+        } else {
+            transport.publish("room", data);
+        }
+    }
+}
+
+
+struct ChatFactory;
+
+impl ServerFactory for ChatFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(ChatProtocol)
+    }
+}
+
+
+fn join(rio: &mut Rio, addr: SocketAddr, count: usize) -> Vec<TcpStream> {
+    let members = (0..count).map(|_| {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }).collect::<Vec<TcpStream>>();
+    rio.run_until(&|rio: &Rio| -> bool { rio.channel_members("room").len() < count });
+    members
+}
+
+
+fn read(stream: &TcpStream, len: usize) -> JoinHandle<Vec<u8>> {
+    let mut stream = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut received = vec![0; len];
+        stream.read_exact(&mut received).unwrap();
+        received
+    })
+}
+
+
+#[test]
+fn test_broadcast() {
+    let mut rio = Rio::new();
+    let addr = rio.listen("127.0.0.1:0", Box::new(ChatFactory)).unwrap().local_addr();
+    let mut members = join(&mut rio, addr, 3);
+    let token = rio.channel_members("room")[0];
+    assert_eq!(rio.joined_channels(token), vec!["room".to_string()]);
+
+    let readers = members.iter().map(|member| read(member, 6)).collect::<Vec<_>>();
+    members[0].write_all(b"hello\n").unwrap();
+    rio.run_until(&|_: &Rio| -> bool { readers.iter().any(|reader| !reader.is_finished()) });
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), b"hello\n");
+    }
+
+    // the members that left do not receive the messages
+    members[2].write_all(b"leave\n").unwrap();
+    rio.run_until(&|rio: &Rio| -> bool { rio.channel_members("room").len() > 2 });
+    assert_eq!(rio.publish("room", b"bye\n"), 2);
+    let readers = members[..2].iter().map(|member| read(member, 4)).collect::<Vec<_>>();
+    rio.run_until(&|_: &Rio| -> bool { readers.iter().any(|reader| !reader.is_finished()) });
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), b"bye\n");
+    }
+    members[2].set_nonblocking(true).unwrap();
+    assert!(members[2].read(&mut [0; 16]).is_err());
+}
+
+
+#[test]
+fn test_member_disconnected() {
+    let mut rio = Rio::new();
+    let addr = rio.listen("127.0.0.1:0", Box::new(ChatFactory)).unwrap().local_addr();
+    let mut members = join(&mut rio, addr, 4);
+
+    // reset, the write fails during the broadcast
+    let reset = members.remove(1);
+    TcpStreamExt::set_linger(&reset, Some(Duration::from_secs(0))).unwrap();
+    drop(reset);
+    // closed, the loop is notified before the broadcast
+    drop(members.remove(1));
+
+    let readers = members.iter().map(|member| read(member, 6)).collect::<Vec<_>>();
+    members[0].write_all(b"hello\n").unwrap();
+    rio.run_until(&|rio: &Rio| -> bool {
+        readers.iter().any(|reader| !reader.is_finished()) || rio.channel_members("room").len() > 2
+    });
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), b"hello\n");
+    }
+    assert_eq!(rio.connections_count(), 2);
+}
@@ -0,0 +1,200 @@
+extern crate janeiro;
+extern crate net2;
+
+use std::cell::{Cell, RefCell};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration;
+
+use net2::TcpStreamExt;
+
+use janeiro::{Rio, RioBuilder, Transport, ServerFactory, OverloadPolicy, Protocol, Reason};
+
+
+struct GreetingProtocol;
+
+impl Protocol for GreetingProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"hello\n");
+    }
+
+    fn data_received(&mut self, _: &[u8], transport: &mut Transport) {
+        transport.hang_up();
+    }
+}
+
+
+/// Reset the peer, held by the test, while its data is being read.
+struct ResettingProtocol {
+    peer: Rc<RefCell<Option<TcpStream>>>,
+    lost: Rc<Cell<Option<Reason>>>,
+}
+
+impl Protocol for ResettingProtocol {
+    fn data_received(&mut self, _: &[u8], _: &mut Transport) {
+        if let Some(peer) = self.peer.borrow_mut().take() {
+            TcpStreamExt::set_linger(&peer, Some(Duration::from_secs(0))).unwrap();
+        }
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        self.lost.set(Some(reason));
+    }
+}
+
+
+struct ResettingFactory {
+    peer: Rc<RefCell<Option<TcpStream>>>,
+    lost: Rc<Cell<Option<Reason>>>,
+}
+
+impl ServerFactory for ResettingFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(ResettingProtocol {
+            peer: self.peer.clone(),
+            lost: self.lost.clone(),
+        })
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+
+struct LimitedFactory {
+    policy: OverloadPolicy,
+    accepted: Rc<Cell<usize>>,
+    limit_reached: Rc<Cell<usize>>,
+}
+
+impl LimitedFactory {
+    fn new(policy: OverloadPolicy) -> LimitedFactory {
+        LimitedFactory {
+            policy,
+            accepted: Rc::new(Cell::new(0)),
+            limit_reached: Rc::new(Cell::new(0)),
+        }
+    }
+}
+
+impl ServerFactory for LimitedFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        self.accepted.set(self.accepted.get() + 1);
+        Box::new(GreetingProtocol)
+    }
+
+    fn max_connections(&self) -> Option<usize> {
+        Some(1)
+    }
+
+    fn overload_policy(&self) -> OverloadPolicy {
+        self.policy.clone()
+    }
+
+    fn limit_reached(&self, _: usize) {
+        self.limit_reached.set(self.limit_reached.get() + 1);
+    }
+}
+
+
+fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    while stream.read(&mut byte).unwrap() == 1 {
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    String::from_utf8(line).unwrap()
+}
+
+
+#[test]
+fn test_pause_accepting() {
+    let factory = LimitedFactory::new(OverloadPolicy::Pause);
+    let accepted = factory.accepted.clone();
+    let limit_reached = factory.limit_reached.clone();
+    let mut rio = Rio::new();
+    let listener = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap();
+
+    let mut first = TcpStream::connect(listener.local_addr()).unwrap();
+    let mut second = TcpStream::connect(listener.local_addr()).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { limit_reached.get() == 0 });
+    rio.run_once();
+    assert_eq!(accepted.get(), 1);
+    assert_eq!(rio.connections_count(), 1);
+    assert_eq!(read_line(&mut first), "hello\n");
+
+    // the second connection is accepted once the first one is closed
+    first.write_all(b"bye\n").unwrap();
+    rio.run_until(&|_: &Rio| -> bool { accepted.get() < 2 });
+    rio.run_once();
+    assert_eq!(read_line(&mut second), "hello\n");
+    assert_eq!(rio.connections_count(), 1);
+}
+
+
+#[test]
+fn test_reject() {
+    let factory = LimitedFactory::new(OverloadPolicy::Reject(b"busy\n".to_vec()));
+    let accepted = factory.accepted.clone();
+    let limit_reached = factory.limit_reached.clone();
+    let mut rio = Rio::new();
+    let listener = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap();
+
+    let mut first = TcpStream::connect(listener.local_addr()).unwrap();
+    let mut second = TcpStream::connect(listener.local_addr()).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { limit_reached.get() == 0 });
+    rio.run_once();
+    assert_eq!(accepted.get(), 1);
+    assert_eq!(read_line(&mut first), "hello\n");
+    assert_eq!(read_line(&mut second), "busy\n");
+    assert_eq!(read_line(&mut second), "");
+}
+
+
+#[test]
+fn test_global_limit() {
+    let factory = LimitedFactory::new(OverloadPolicy::Pause);
+    let accepted = factory.accepted.clone();
+    let limit_reached = factory.limit_reached.clone();
+    let mut rio = Rio::new();
+    rio.set_max_connections(0);
+    let listener = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap();
+
+    let _client = TcpStream::connect(listener.local_addr()).unwrap();
+    rio.run_once();
+    assert_eq!(accepted.get(), 0);
+    assert_eq!(limit_reached.get(), 1);
+    assert_eq!(rio.connections_count(), 0);
+}
+
+
+#[test]
+fn test_reset_while_reading() {
+    let peer = Rc::new(RefCell::new(None));
+    let lost = Rc::new(Cell::new(None));
+    let factory = ResettingFactory {
+        peer: peer.clone(),
+        lost: lost.clone(),
+    };
+    // the data is read in several chunks, the reset comes after the first
+    let mut rio = RioBuilder::new().read_buffer_size(4).build().unwrap();
+    let listener = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap();
+
+    let mut stream = TcpStream::connect(listener.local_addr()).unwrap();
+    stream.write_all(b"hello world\n").unwrap();
+    *peer.borrow_mut() = Some(stream);
+    rio.run_until(&|_: &Rio| -> bool { lost.get().is_none() });
+    assert_eq!(lost.get(), Some(Reason::ConnectionError));
+    assert_eq!(rio.connections_count(), 0);
+
+    // the connection does not count in the limit of the listener anymore
+    let mut second = TcpStream::connect(listener.local_addr()).unwrap();
+    second.write_all(b"hello\n").unwrap();
+    rio.run_until(&|rio: &Rio| -> bool { rio.connections_count() == 0 });
+}
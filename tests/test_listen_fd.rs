@@ -0,0 +1,60 @@
+extern crate janeiro;
+
+use std::cell::Cell;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::rc::Rc;
+use std::thread;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Reason};
+
+
+struct HangUpProtocol {
+    closed: Rc<Cell<bool>>,
+}
+
+impl Protocol for HangUpProtocol {
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        transport.write(data);
+        transport.hang_up();
+    }
+
+    fn connection_lost(&mut self, _: Reason) {
+        self.closed.set(true);
+    }
+}
+
+
+struct HangUpFactory {
+    closed: Rc<Cell<bool>>,
+}
+
+impl ServerFactory for HangUpFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(HangUpProtocol { closed: self.closed.clone() })
+    }
+}
+
+
+#[test]
+fn test_listen_fd() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let closed = Rc::new(Cell::new(false));
+    let mut rio = Rio::new();
+    rio.listen_fd(listener.into_raw_fd(), Box::new(HangUpFactory { closed: closed.clone() })).unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"inherited\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        line
+    });
+
+    rio.run_until(&|_: &Rio| -> bool { !closed.get() });
+    assert_eq!(client.join().unwrap(), "inherited\n");
+}
@@ -0,0 +1,119 @@
+extern crate janeiro;
+
+use std::io::prelude::*;
+use std::cell::Cell;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, ListenerBuilder};
+use janeiro::listener::run_threads;
+
+
+struct ThreadNameProtocol;
+
+impl Protocol for ThreadNameProtocol {
+    fn data_received(&mut self, _: &[u8], transport: &mut Transport) {
+        let name = format!("{}\n", std::thread::current().name().unwrap());
+        transport.write(name.as_bytes());
+        transport.hang_up();
+    }
+}
+
+
+struct ThreadNameFactory;
+
+impl ServerFactory for ThreadNameFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(ThreadNameProtocol)
+    }
+}
+
+
+#[test]
+fn test_run_threads() {
+    let builder = ListenerBuilder::new("127.0.0.1:0").unwrap();
+    let (addr, handles) = run_threads(2, builder, || -> Box<dyn ServerFactory> {
+        Box::new(ThreadNameFactory)
+    }).unwrap();
+    assert_eq!(handles.len(), 2);
+    assert!(addr.port() != 0);
+
+    for _ in 0..8 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"who\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.starts_with("janeiro-"), "unexpected reply {:?}", line);
+    }
+
+    for handle in handles {
+        handle.shutdown().unwrap();
+    }
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+
+#[test]
+fn test_stopper() {
+    let mut rio = Rio::new();
+    rio.listen("127.0.0.1:0", Box::new(ThreadNameFactory)).unwrap();
+    let stopper = rio.stopper().unwrap();
+    let remote = stopper.clone();
+    let thread = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        remote.stop().unwrap();
+    });
+    // stopped while waiting for events
+    rio.run_forever();
+    thread.join().unwrap();
+    drop(rio);
+    assert!(stopper.stop().is_err());
+}
+
+
+#[test]
+fn test_listen_on_port_zero() {
+    let mut rio = Rio::new();
+    let first = rio.listen("127.0.0.1:0", Box::new(ThreadNameFactory)).unwrap();
+    let second = rio.listen_with(&ListenerBuilder::new("127.0.0.1:0").unwrap(),
+                                 Box::new(ThreadNameFactory)).unwrap();
+    assert!(first.local_addr().port() != 0);
+    assert!(first.local_addr() != second.local_addr());
+    assert_eq!(rio.local_addr(first.token()).unwrap(), first.local_addr());
+    assert_eq!(rio.local_addr(second.token()).unwrap(), second.local_addr());
+
+    assert!(TcpStream::connect(first.local_addr()).is_ok());
+    assert!(rio.listen("127.0.0.1", Box::new(ThreadNameFactory)).is_err());
+}
+
+
+#[test]
+fn test_close_listener() {
+    let mut rio = Rio::new();
+    let closing = rio.listen("127.0.0.1:0", Box::new(ThreadNameFactory)).unwrap();
+    let running = rio.listen("127.0.0.1:0", Box::new(ThreadNameFactory)).unwrap();
+
+    let mut client = TcpStream::connect(closing.local_addr()).unwrap();
+    rio.run_once();
+
+    let drained = Rc::new(Cell::new(false));
+    let on_drained = drained.clone();
+    rio.close_listener(closing.token(), Some(Box::new(move |_: &mut Rio| on_drained.set(true)))).unwrap();
+    assert!(!rio.contains(closing.token()));
+    assert!(rio.contains(running.token()));
+    assert!(rio.close_listener(closing.token(), None).is_err());
+    assert!(TcpStream::connect(closing.local_addr()).is_err());
+    assert!(!drained.get());
+
+    // the established connection is still served
+    client.write_all(b"who\n").unwrap();
+    rio.run_until(&|_: &Rio| -> bool { !drained.get() });
+    let mut line = String::new();
+    BufReader::new(client).read_line(&mut line).unwrap();
+    assert_eq!(line, "test_close_listener\n");
+
+    assert!(TcpStream::connect(running.local_addr()).is_ok());
+}
@@ -0,0 +1,59 @@
+extern crate janeiro;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use janeiro::{Rio, Transport, Protocol, Reason};
+use janeiro::testing::{EchoFactory, Loopback};
+
+
+#[derive(Default)]
+struct Received {
+    data: Vec<u8>,
+    reason: Option<Reason>,
+}
+
+
+struct ClientProtocol {
+    received: Rc<RefCell<Received>>,
+}
+
+impl Protocol for ClientProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"loopback\n");
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.received.borrow_mut().data.extend_from_slice(data);
+        transport.hang_up();
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        self.received.borrow_mut().reason = Some(reason);
+    }
+}
+
+
+#[test]
+fn test_loopback() {
+    let mut loopback = Loopback::new(Box::new(EchoFactory)).unwrap();
+    assert_eq!(loopback.addr().ip().to_string(), "127.0.0.1");
+    assert!(loopback.addr().port() != 0);
+
+    let received = Rc::new(RefCell::new(Received::default()));
+    let client = loopback.connect(Box::new(ClientProtocol { received: received.clone() })).unwrap();
+
+    let done = loopback.run_until(Duration::from_secs(5), |rio: &Rio| !rio.contains(client));
+    assert!(done);
+    assert_eq!(&received.borrow().data[..], b"loopback\n");
+    assert_eq!(received.borrow().reason, Some(Reason::HangUp));
+}
+
+
+#[test]
+fn test_loopback_timeout() {
+    let mut loopback = Loopback::new(Box::new(EchoFactory)).unwrap();
+    let done = loopback.run_until(Duration::from_millis(10), |_: &Rio| false);
+    assert!(!done);
+}
@@ -0,0 +1,111 @@
+extern crate janeiro;
+
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol};
+use janeiro::proxy::ProxyFactory;
+use janeiro::testing::EchoFactory;
+
+
+/// Count the bytes received, and reply with the count once the
+/// peer has nothing more to send.
+struct CountProtocol {
+    count: usize,
+}
+
+impl Protocol for CountProtocol {
+    fn data_received(&mut self, data: &[u8], _: &mut Transport) {
+        self.count += data.len();
+    }
+
+    fn eof_received(&mut self, transport: &mut Transport) -> bool {
+        transport.write(format!("{}\n", self.count).as_bytes());
+        transport.hang_up();
+        true
+    }
+}
+
+
+struct CountFactory;
+
+impl ServerFactory for CountFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(CountProtocol { count: 0 })
+    }
+}
+
+
+/// Send the data, shut down the writing side and read until the end.
+fn request(addr: SocketAddr, data: Vec<u8>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // the proxy may have closed the connection already
+        if stream.write_all(&data[..]).is_ok() {
+            let _ = stream.shutdown(Shutdown::Write);
+        }
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        received
+    })
+}
+
+
+fn run_proxy(backend: Box<dyn ServerFactory>, data: Vec<u8>) -> Vec<u8> {
+    let mut rio = Rio::new();
+    let backend = rio.listen("127.0.0.1:0", backend).unwrap();
+    let proxy = ProxyFactory::new(&backend.local_addr().to_string()).unwrap();
+    let proxy = rio.listen("127.0.0.1:0", Box::new(proxy)).unwrap();
+
+    let client = request(proxy.local_addr(), data);
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    client.join().unwrap()
+}
+
+
+#[test]
+fn test_proxy() {
+    assert_eq!(run_proxy(Box::new(EchoFactory), b"hello\n".to_vec()), b"hello\n");
+}
+
+
+#[test]
+fn test_proxy_half_close() {
+    let data = vec![42; 4 * 1024 * 1024];
+    assert_eq!(run_proxy(Box::new(CountFactory), data), b"4194304\n");
+}
+
+
+#[test]
+fn test_proxy_backend_down() {
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = backend.local_addr().unwrap();
+    drop(backend);
+
+    let mut rio = Rio::new();
+    let proxy = rio.listen("127.0.0.1:0", Box::new(ProxyFactory::new(&addr.to_string()).unwrap())).unwrap();
+    let client = request(proxy.local_addr(), b"hello\n".to_vec());
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    assert_eq!(client.join().unwrap(), b"");
+}
+
+
+#[test]
+fn test_proxy_connect_failed() {
+    // the broadcast address is refused by connect itself
+    let mut rio = Rio::new();
+    let proxy = rio.listen("127.0.0.1:0", Box::new(ProxyFactory::new("255.255.255.255:80").unwrap())).unwrap();
+    let addr = proxy.local_addr();
+    // the client waits for the backend to speak first
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).map(|_| received)
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    assert_eq!(client.join().unwrap().unwrap(), b"");
+    assert_eq!(rio.connections_count(), 0);
+}
@@ -0,0 +1,180 @@
+extern crate janeiro;
+
+use std::cell::{Cell, RefCell};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Reason, ListenerBuilder};
+use janeiro::proxy::ProxyFactory;
+use janeiro::proxy_header::{ProxyHeader, ProxyVersion};
+
+
+/// Record the address of the peer and the data received.
+struct RecordProtocol {
+    peer: Rc<RefCell<Option<SocketAddr>>>,
+    received: Rc<RefCell<Vec<u8>>>,
+    closed: Rc<Cell<usize>>,
+}
+
+impl Protocol for RecordProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        *self.peer.borrow_mut() = Some(transport.peer_addr().unwrap());
+    }
+
+    fn data_received(&mut self, data: &[u8], _: &mut Transport) {
+        self.received.borrow_mut().extend_from_slice(data);
+    }
+
+    fn connection_lost(&mut self, _: Reason) {
+        self.closed.set(self.closed.get() + 1);
+    }
+}
+
+
+#[derive(Default)]
+struct RecordFactory {
+    peer: Rc<RefCell<Option<SocketAddr>>>,
+    received: Rc<RefCell<Vec<u8>>>,
+    closed: Rc<Cell<usize>>,
+}
+
+impl ServerFactory for RecordFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(RecordProtocol {
+            peer: self.peer.clone(),
+            received: self.received.clone(),
+            closed: self.closed.clone(),
+        })
+    }
+}
+
+
+fn listen_proxied(rio: &mut Rio, factory: Box<dyn ServerFactory>) -> SocketAddr {
+    let builder = ListenerBuilder::new("127.0.0.1:0").unwrap().proxy_protocol(true);
+    rio.listen_with(&builder, factory).unwrap().local_addr()
+}
+
+
+#[test]
+fn test_read_header() {
+    let source: SocketAddr = FromStr::from_str("10.1.2.3:5678").unwrap();
+    let destination: SocketAddr = FromStr::from_str("10.1.2.4:80").unwrap();
+    for version in &[ProxyVersion::V1, ProxyVersion::V2] {
+        let factory = RecordFactory::default();
+        let (peer, received, closed) = (factory.peer.clone(), factory.received.clone(), factory.closed.clone());
+        let mut rio = Rio::new();
+        let addr = listen_proxied(&mut rio, Box::new(factory));
+
+        let mut data = ProxyHeader::new(source, destination).encode(*version);
+        data.extend_from_slice(b"hello");
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            // the header may be received in many reads
+            stream.write_all(&data[..5]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(&data[5..]).unwrap();
+        });
+        rio.run_until(&|_: &Rio| -> bool { closed.get() == 0 });
+        client.join().unwrap();
+        assert_eq!(*peer.borrow(), Some(source));
+        assert_eq!(&received.borrow()[..], b"hello");
+    }
+}
+
+
+#[test]
+fn test_invalid_header() {
+    let factory = RecordFactory::default();
+    let peer = factory.peer.clone();
+    let closed = factory.closed.clone();
+    let mut rio = Rio::new();
+    let addr = listen_proxied(&mut rio, Box::new(factory));
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received);
+        received
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    assert_eq!(client.join().unwrap(), b"");
+    // the protocol is not made
+    assert_eq!(*peer.borrow(), None);
+    assert_eq!(closed.get(), 0);
+    assert_eq!(rio.connections_count(), 0);
+}
+
+
+/// Reply with the address of the peer, then hang up.
+struct WhoAmIProtocol;
+
+impl Protocol for WhoAmIProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        let peer = transport.peer_addr().unwrap();
+        transport.write(peer.to_string().as_bytes());
+        transport.hang_up();
+    }
+}
+
+
+struct WhoAmIFactory;
+
+impl ServerFactory for WhoAmIFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(WhoAmIProtocol)
+    }
+}
+
+
+#[test]
+fn test_proxy_header() {
+    for version in &[ProxyVersion::V1, ProxyVersion::V2] {
+        let mut rio = Rio::new();
+        let backend = listen_proxied(&mut rio, Box::new(WhoAmIFactory));
+        let proxy = ProxyFactory::new(&backend.to_string()).unwrap().proxy_header(*version);
+        let proxy = rio.listen("127.0.0.1:0", Box::new(proxy)).unwrap().local_addr();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(proxy).unwrap();
+            let mut received = String::new();
+            let _ = stream.read_to_string(&mut received);
+            (stream.local_addr().unwrap(), received)
+        });
+        rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+        let (addr, received) = client.join().unwrap();
+        assert_eq!(received, addr.to_string());
+    }
+}
+
+
+#[test]
+fn test_connect_proxied() {
+    let factory = RecordFactory::default();
+    let (peer, received) = (factory.peer.clone(), factory.received.clone());
+    let mut rio = Rio::new();
+    let addr = listen_proxied(&mut rio, Box::new(factory));
+
+    let source: SocketAddr = FromStr::from_str("[2001:db8::1]:4242").unwrap();
+    let header = ProxyHeader::new(source, FromStr::from_str("[2001:db8::2]:443").unwrap());
+    let client = SendProtocol { data: b"hello" };
+    rio.connect_proxied(&addr.to_string(), Box::new(client), &header, ProxyVersion::V1).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { received.borrow().len() < 5 });
+    assert_eq!(*peer.borrow(), Some(source));
+    assert_eq!(&received.borrow()[..], b"hello");
+}
+
+
+struct SendProtocol {
+    data: &'static [u8],
+}
+
+impl Protocol for SendProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(self.data);
+    }
+}
@@ -0,0 +1,194 @@
+extern crate janeiro;
+extern crate mio;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use mio::Token;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol};
+use janeiro::redis::{Decoder, Message, RedisClient, Value};
+
+
+#[derive(Default)]
+struct Store {
+    data: HashMap<Vec<u8>, Vec<u8>>,
+    // the subscribed connections, and if they speak RESP3
+    subscribers: HashMap<Vec<u8>, Vec<(Token, bool)>>,
+}
+
+
+/// A stand-in for Redis, for a few commands.
+struct RespProtocol {
+    store: Rc<RefCell<Store>>,
+    decoder: Decoder,
+    resp3: bool,
+}
+
+impl RespProtocol {
+    fn execute(&mut self, args: Vec<Vec<u8>>, transport: &mut Transport) -> Vec<Value> {
+        let mut store = self.store.borrow_mut();
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        match &command[..] {
+            "HELLO" => {
+                self.resp3 = args.get(1).map(|version| &version[..] == b"3").unwrap_or(false);
+                vec![Value::Map(vec![(Value::SimpleString("proto".to_string()),
+                                      Value::Integer(if self.resp3 { 3 } else { 2 }))])]
+            }
+            "PING" => vec![Value::SimpleString("PONG".to_string())],
+            "SET" => {
+                store.data.insert(args[1].clone(), args[2].clone());
+                vec![Value::SimpleString("OK".to_string())]
+            }
+            "GET" => vec![store.data.get(&args[1]).map(|value| Value::BulkString(value.clone())).unwrap_or(Value::Null)],
+            "INCR" => {
+                let value = store.data.entry(args[1].clone()).or_insert_with(|| b"0".to_vec());
+                let incremented = String::from_utf8_lossy(value).parse::<i64>().unwrap() + 1;
+                *value = incremented.to_string().into_bytes();
+                vec![Value::Integer(incremented)]
+            }
+            "SUBSCRIBE" => {
+                args[1..].iter().enumerate().map(|(i, channel)| {
+                    store.subscribers.entry(channel.clone()).or_default().push((transport.token(), self.resp3));
+                    self.push(vec![Value::BulkString(b"subscribe".to_vec()),
+                                   Value::BulkString(channel.clone()),
+                                   Value::Integer(i as i64 + 1)])
+                }).collect()
+            }
+            "PUBLISH" => {
+                let subscribers = store.subscribers.get(&args[1]).cloned().unwrap_or_default();
+                for &(token, resp3) in &subscribers {
+                    let message = vec![Value::BulkString(b"message".to_vec()),
+                                       Value::BulkString(args[1].clone()),
+                                       Value::BulkString(args[2].clone())];
+                    let mut data = Vec::new();
+                    if resp3 { Value::Push(message) } else { Value::Array(message) }.encode(&mut data);
+                    transport.peer(token).write(&data);
+                }
+                vec![Value::Integer(subscribers.len() as i64)]
+            }
+            _ => vec![Value::Error(format!("ERR unknown command '{}'", command))],
+        }
+    }
+
+    fn push(&self, values: Vec<Value>) -> Value {
+        if self.resp3 { Value::Push(values) } else { Value::Array(values) }
+    }
+}
+
+impl Protocol for RespProtocol {
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.decoder.feed(data);
+        while let Some(command) = self.decoder.decode().unwrap() {
+            let args = match command {
+                Value::Array(args) => args.into_iter().map(|arg| arg.as_bytes().unwrap().to_vec()).collect(),
+                _ => panic!("Invalid command {:?}", command),
+            };
+            let mut data = Vec::new();
+            for reply in self.execute(args, transport) {
+                reply.encode(&mut data);
+            }
+            transport.write(&data);
+        }
+    }
+}
+
+
+struct RespFactory {
+    store: Rc<RefCell<Store>>,
+}
+
+impl ServerFactory for RespFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(RespProtocol {
+            store: self.store.clone(),
+            decoder: Decoder::new(),
+            resp3: false,
+        })
+    }
+}
+
+
+fn start_server(rio: &mut Rio) -> (String, Rc<RefCell<Store>>) {
+    let store = Rc::new(RefCell::new(Store::default()));
+    let listener = rio.listen("127.0.0.1:0", Box::new(RespFactory { store: store.clone() })).unwrap();
+    (listener.local_addr().to_string(), store)
+}
+
+
+fn record(replies: &Rc<RefCell<Vec<Value>>>) -> impl FnOnce(Value) + 'static {
+    let replies = replies.clone();
+    move |value| replies.borrow_mut().push(value)
+}
+
+
+#[test]
+fn test_pipelining() {
+    let mut rio = Rio::new();
+    let (addr, _) = start_server(&mut rio);
+    let client = RedisClient::connect(&mut rio, &addr).unwrap();
+
+    let replies = Rc::new(RefCell::new(Vec::new()));
+    client.command(&["SET", "key", "value"], record(&replies));
+    client.command(&["GET", "key"], record(&replies));
+    client.command(&["GET", "missing"], record(&replies));
+    for _ in 0..3 {
+        client.command(&["INCR", "counter"], record(&replies));
+    }
+    client.command(&["NOPE"], record(&replies));
+    client.flush(&mut rio).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { client.pending() > 0 });
+    assert_eq!(*replies.borrow(),
+               vec![Value::SimpleString("OK".to_string()),
+                    Value::BulkString(b"value".to_vec()),
+                    Value::Null,
+                    Value::Integer(1),
+                    Value::Integer(2),
+                    Value::Integer(3),
+                    Value::Error("ERR unknown command 'NOPE'".to_string())]);
+}
+
+
+fn test_pubsub(resp3: bool) {
+    let mut rio = Rio::new();
+    let (addr, store) = start_server(&mut rio);
+
+    let subscriber = RedisClient::connect(&mut rio, &addr).unwrap();
+    let messages = Rc::new(RefCell::new(Vec::new()));
+    let received = messages.clone();
+    subscriber.on_message(move |message| received.borrow_mut().push(message));
+    if resp3 {
+        subscriber.command(&["HELLO", "3"], |_| {});
+    }
+    subscriber.subscribe(&["news"]);
+    subscriber.flush(&mut rio).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { store.borrow().subscribers.is_empty() });
+
+    let publisher = RedisClient::connect(&mut rio, &addr).unwrap();
+    let replies = Rc::new(RefCell::new(Vec::new()));
+    publisher.command(&["PUBLISH", "news", "hello"], record(&replies));
+    publisher.command(&["PUBLISH", "other", "ignored"], record(&replies));
+    publisher.flush(&mut rio).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { messages.borrow().is_empty() || publisher.pending() > 0 });
+
+    assert_eq!(*replies.borrow(), vec![Value::Integer(1), Value::Integer(0)]);
+    assert_eq!(*messages.borrow(),
+               vec![Message {
+                        channel: b"news".to_vec(),
+                        pattern: None,
+                        payload: b"hello".to_vec(),
+                    }]);
+}
+
+
+#[test]
+fn test_pubsub_resp2() {
+    test_pubsub(false);
+}
+
+
+#[test]
+fn test_pubsub_resp3() {
+    test_pubsub(true);
+}
@@ -0,0 +1,33 @@
+extern crate janeiro;
+extern crate nix;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use janeiro::{Rio, Signal};
+use nix::sys::signal::raise;
+
+
+#[test]
+fn test_signal_handler() {
+    let mut rio = Rio::new();
+    let reloaded = Rc::new(Cell::new(0));
+    {
+        let reloaded = reloaded.clone();
+        rio.add_signal_handler(Signal::SIGUSR2, Box::new(move |_: &mut Rio| {
+            reloaded.set(reloaded.get() + 1);
+        })).unwrap();
+    }
+    rio.add_signal_handler(Signal::SIGUSR1, Box::new(|rio: &mut Rio| rio.stop())).unwrap();
+
+    // signals are raised on the current thread, where they are blocked.
+    raise(Signal::SIGUSR2).unwrap();
+    raise(Signal::SIGUSR1).unwrap();
+    rio.run_forever();
+
+    assert_eq!(reloaded.get(), 1);
+
+    assert!(rio.remove_signal_handler(Signal::SIGUSR2).unwrap());
+    assert!(!rio.remove_signal_handler(Signal::SIGUSR2).unwrap());
+    assert!(rio.remove_signal_handler(Signal::SIGUSR1).unwrap());
+}
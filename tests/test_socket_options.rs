@@ -0,0 +1,90 @@
+extern crate janeiro;
+extern crate net2;
+
+use std::cell::RefCell;
+use std::mem::ManuallyDrop;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use net2::TcpStreamExt;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, SocketOptions, Keepalive};
+
+
+#[derive(Debug, PartialEq)]
+struct Applied {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    linger: Option<Duration>,
+    ttl: u32,
+}
+
+
+/// Read back the options of the socket once the connection is made.
+struct ReadOptionsProtocol {
+    applied: Rc<RefCell<Vec<Applied>>>,
+}
+
+impl Protocol for ReadOptionsProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(transport.raw_fd().unwrap()) });
+        self.applied.borrow_mut().push(Applied {
+            nodelay: stream.nodelay().unwrap(),
+            keepalive: TcpStreamExt::keepalive(&*stream).unwrap(),
+            linger: TcpStreamExt::linger(&*stream).unwrap(),
+            ttl: stream.ttl().unwrap(),
+        });
+        transport.hang_up();
+    }
+}
+
+
+struct OptionsFactory {
+    applied: Rc<RefCell<Vec<Applied>>>,
+}
+
+impl ServerFactory for OptionsFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(ReadOptionsProtocol { applied: self.applied.clone() })
+    }
+
+    fn socket_options(&self) -> SocketOptions {
+        SocketOptions::new()
+            .nodelay(true)
+            .keepalive(Keepalive::new(Duration::from_secs(30)))
+            .ttl(42)
+    }
+}
+
+
+#[test]
+fn test_accepted_and_connected_options() {
+    let mut rio = Rio::new();
+    let accepted = Rc::new(RefCell::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    rio.listen_fd(listener.into_raw_fd(), Box::new(OptionsFactory { applied: accepted.clone() })).unwrap();
+
+    let connected = Rc::new(RefCell::new(Vec::new()));
+    let options = SocketOptions::new().linger(Some(Duration::from_secs(1))).ttl(43);
+    rio.connect_with(&addr.to_string(), Box::new(ReadOptionsProtocol { applied: connected.clone() }), &options)
+        .unwrap();
+    rio.run_until(&|_: &Rio| -> bool { accepted.borrow().is_empty() || connected.borrow().is_empty() });
+
+    assert_eq!(accepted.borrow()[0],
+               Applied {
+                   nodelay: true,
+                   keepalive: Some(Duration::from_secs(30)),
+                   linger: None,
+                   ttl: 42,
+               });
+    assert_eq!(connected.borrow()[0],
+               Applied {
+                   nodelay: false,
+                   keepalive: None,
+                   linger: Some(Duration::from_secs(1)),
+                   ttl: 43,
+               });
+}
@@ -0,0 +1,145 @@
+extern crate janeiro;
+
+use std::cell::{Cell, RefCell};
+use std::io::prelude::*;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::thread;
+
+use janeiro::{Rio, Transport, Protocol, Reason};
+use janeiro::socks::{Socks5Factory, Socks5Proxy};
+use janeiro::testing::EchoFactory;
+
+
+/// Send a line once connected, and hang up once it is echoed.
+struct LineProtocol {
+    received: Rc<RefCell<Vec<u8>>>,
+    reason: Rc<Cell<Option<Reason>>>,
+}
+
+impl Protocol for LineProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"hello\n");
+    }
+
+    fn data_received(&mut self, data: &[u8], transport: &mut Transport) {
+        self.received.borrow_mut().extend_from_slice(data);
+        if self.received.borrow().ends_with(b"\n") {
+            transport.hang_up();
+        }
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        self.reason.set(Some(reason));
+    }
+}
+
+
+/// Connect through a SOCKS server of the loop to an echo server.
+fn echo_through(factory: Socks5Factory, proxy: &dyn Fn(SocketAddr) -> Socks5Proxy, target: &dyn Fn(SocketAddr) -> String)
+                -> (Vec<u8>, Option<Reason>) {
+    let mut rio = Rio::new();
+    let echo = rio.listen("127.0.0.1:0", Box::new(EchoFactory)).unwrap().local_addr();
+    let socks = rio.listen("127.0.0.1:0", Box::new(factory)).unwrap().local_addr();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let reason = Rc::new(Cell::new(None));
+    let client = LineProtocol {
+        received: received.clone(),
+        reason: reason.clone(),
+    };
+    rio.connect_through(&proxy(socks), &target(echo), Box::new(client)).unwrap();
+    rio.run_until(&|_: &Rio| -> bool { reason.get().is_none() });
+    let received = received.borrow().clone();
+    (received, reason.get())
+}
+
+
+#[test]
+fn test_no_auth() {
+    let (received, reason) = echo_through(Socks5Factory::new(),
+                                          &|socks| Socks5Proxy::new(&socks.to_string()).unwrap(),
+                                          &|echo| echo.to_string());
+    assert_eq!(received, b"hello\n");
+    assert_eq!(reason, Some(Reason::HangUp));
+}
+
+
+#[test]
+fn test_domain_name() {
+    let (received, reason) = echo_through(Socks5Factory::new(),
+                                          &|socks| Socks5Proxy::new(&socks.to_string()).unwrap(),
+                                          &|echo| format!("localhost:{}", echo.port()));
+    assert_eq!(received, b"hello\n");
+    assert_eq!(reason, Some(Reason::HangUp));
+}
+
+
+#[test]
+fn test_user_password() {
+    let factory = Socks5Factory::new().user("alice", "secret");
+    let (received, reason) = echo_through(factory.clone(),
+                                          &|socks| Socks5Proxy::new(&socks.to_string()).unwrap().credentials("alice", "secret"),
+                                          &|echo| echo.to_string());
+    assert_eq!(received, b"hello\n");
+    assert_eq!(reason, Some(Reason::HangUp));
+
+    let (received, reason) = echo_through(factory.clone(),
+                                          &|socks| Socks5Proxy::new(&socks.to_string()).unwrap().credentials("alice", "wrong"),
+                                          &|echo| echo.to_string());
+    assert_eq!(received, b"");
+    assert_eq!(reason, Some(Reason::ConnectionError));
+
+    let (received, reason) = echo_through(factory,
+                                          &|socks| Socks5Proxy::new(&socks.to_string()).unwrap(),
+                                          &|echo| echo.to_string());
+    assert_eq!(received, b"");
+    assert_eq!(reason, Some(Reason::ConnectionError));
+}
+
+
+#[test]
+fn test_blocking_client() {
+    let mut rio = Rio::new();
+    let echo = rio.listen("127.0.0.1:0", Box::new(EchoFactory)).unwrap().local_addr();
+    let socks = rio.listen("127.0.0.1:0", Box::new(Socks5Factory::new())).unwrap().local_addr();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(socks).unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&echo.port().to_be_bytes());
+        // the data sent with the request is forwarded once connected
+        request.extend_from_slice(b"hello\n");
+        stream.write_all(&request[..]).unwrap();
+        let mut received = [0; 18];
+        stream.read_exact(&mut received).unwrap();
+        received.to_vec()
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    let received = client.join().unwrap();
+    assert_eq!(&received[..2], &[5, 0]);
+    assert_eq!(&received[2..12], &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&received[12..], b"hello\n");
+}
+
+
+#[test]
+fn test_connection_refused() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut rio = Rio::new();
+    let socks = rio.listen("127.0.0.1:0", Box::new(Socks5Factory::new())).unwrap().local_addr();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(socks).unwrap();
+        let mut request = vec![5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&closed.port().to_be_bytes());
+        stream.write_all(&request[..]).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    rio.run_until(&|_: &Rio| -> bool { !client.is_finished() });
+    // the error is replied once the connect failed
+    assert_eq!(client.join().unwrap(), &[5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(rio.connections_count(), 0);
+}
@@ -0,0 +1,99 @@
+extern crate janeiro;
+extern crate nix;
+
+use std::cell::RefCell;
+use std::os::unix::io::RawFd;
+use std::process::{Command, ExitStatus};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use janeiro::{Rio, Signal, SubprocessProtocol, SubprocessTransport};
+use nix::sys::signal::raise;
+
+
+#[derive(Default)]
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    status: Option<ExitStatus>,
+}
+
+
+struct CollectProtocol {
+    input: &'static [u8],
+    output: Rc<RefCell<Output>>,
+}
+
+impl SubprocessProtocol for CollectProtocol {
+    fn connection_made(&mut self, transport: &mut SubprocessTransport) {
+        transport.write(self.input);
+        transport.close_stdin();
+    }
+
+    fn pipe_data_received(&mut self, fd: RawFd, data: &[u8], _: &mut SubprocessTransport) {
+        let mut output = self.output.borrow_mut();
+        match fd {
+            1 => output.stdout.extend_from_slice(data),
+            2 => output.stderr.extend_from_slice(data),
+            _ => panic!("Unexpected fd {}", fd),
+        }
+    }
+
+    fn process_exited(&mut self, status: ExitStatus) {
+        self.output.borrow_mut().status = Some(status);
+    }
+}
+
+
+fn run(command: Command, input: &'static [u8]) -> Rc<RefCell<Output>> {
+    let output = Rc::new(RefCell::new(Output::default()));
+    let protocol = CollectProtocol { input, output: output.clone() };
+    let mut rio = Rio::new();
+    let token = rio.spawn(command, Box::new(protocol)).unwrap();
+    rio.run_until(&|rio: &Rio| -> bool { rio.contains(token) });
+    output
+}
+
+
+#[test]
+fn test_spawn_write_stdin() {
+    let output = run(Command::new("cat"), b"teleport");
+    let output = output.borrow();
+    assert_eq!(&output.stdout[..], b"teleport");
+    assert!(output.stderr.is_empty());
+    assert!(output.status.unwrap().success());
+}
+
+
+#[test]
+fn test_spawn_read_stderr() {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("echo out; echo err >&2; exit 3");
+    let output = run(command, b"");
+    let output = output.borrow();
+    assert_eq!(&output.stdout[..], b"out\n");
+    assert_eq!(&output.stderr[..], b"err\n");
+    assert_eq!(output.status.unwrap().code(), Some(3));
+}
+
+
+#[test]
+fn test_exit_signal() {
+    let output = Rc::new(RefCell::new(Output::default()));
+    let protocol = CollectProtocol { input: b"", output: output.clone() };
+    let mut rio = Rio::new();
+    // the output stays open after the exit, inherited by the child
+    let mut command = Command::new("sh");
+    command.arg("-c").arg("sleep 1 &");
+    let token = rio.spawn(command, Box::new(protocol)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    // the SIGCHLD of the exit may be delivered to another thread of the tests
+    raise(Signal::SIGCHLD).unwrap();
+
+    let start = Instant::now();
+    rio.run_until(&|rio: &Rio| -> bool { rio.contains(token) });
+    // reaped once the signal is received, not after the poll timeout
+    assert!(start.elapsed() < Duration::from_millis(250));
+    assert!(output.borrow().status.unwrap().success());
+}
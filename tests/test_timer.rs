@@ -0,0 +1,86 @@
+extern crate janeiro;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use janeiro::Rio;
+use janeiro::testing::ManualClock;
+
+
+#[test]
+pub fn test_call_later() {
+    let start = Instant::now();
+    let clock = ManualClock::new();
+    let mut rio = Rio::with_clock(Box::new(clock.clone()));
+    let called = Rc::new(RefCell::new(Vec::new()));
+
+    let cb_called = called.clone();
+    rio.call_later(Duration::from_secs(60), Box::new(move |_: &mut Rio| cb_called.borrow_mut().push("slow")));
+    let cb_called = called.clone();
+    rio.call_later(Duration::from_secs(10), Box::new(move |_: &mut Rio| cb_called.borrow_mut().push("fast")));
+
+    rio.run_once();
+    assert!(called.borrow().is_empty());
+
+    clock.advance(Duration::from_secs(10));
+    rio.run_once();
+    assert_eq!(*called.borrow(), vec!["fast"]);
+
+    clock.advance(Duration::from_secs(3600));
+    rio.run_once();
+    assert_eq!(*called.borrow(), vec!["fast", "slow"]);
+
+    // the loop does not sleep with the manual clock
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+
+#[test]
+pub fn test_cancel_timer() {
+    let clock = ManualClock::new();
+    let mut rio = Rio::with_clock(Box::new(clock.clone()));
+    let called = Rc::new(RefCell::new(false));
+
+    let cb_called = called.clone();
+    let timer = rio.call_later(Duration::from_secs(1), Box::new(move |_: &mut Rio| *cb_called.borrow_mut() = true));
+    assert_eq!(timer.deadline(), rio.now() + Duration::from_secs(1));
+    assert!(rio.cancel_timer(timer));
+    assert!(!rio.cancel_timer(timer));
+
+    clock.advance(Duration::from_secs(1));
+    rio.run_once();
+    assert!(!*called.borrow());
+}
+
+
+#[test]
+pub fn test_timer_scheduled_by_timer() {
+    let clock = ManualClock::new();
+    let mut rio = Rio::with_clock(Box::new(clock.clone()));
+    let count = Rc::new(RefCell::new(0));
+
+    let cb_count = count.clone();
+    rio.call_later(Duration::from_secs(0), Box::new(move |rio: &mut Rio| {
+        *cb_count.borrow_mut() += 1;
+        let cb_count = cb_count.clone();
+        rio.call_later(Duration::from_secs(0), Box::new(move |_: &mut Rio| *cb_count.borrow_mut() += 1));
+    }));
+
+    rio.run_once();
+    assert_eq!(*count.borrow(), 1);
+    rio.run_once();
+    assert_eq!(*count.borrow(), 2);
+}
+
+
+#[test]
+pub fn test_stop_from_timer() {
+    let mut rio = Rio::new();
+    let start = Instant::now();
+    rio.call_later(Duration::from_millis(50), Box::new(|rio: &mut Rio| rio.stop()));
+    rio.run_forever();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50));
+    assert!(elapsed < Duration::from_millis(450));
+}
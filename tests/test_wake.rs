@@ -0,0 +1,69 @@
+extern crate janeiro;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Reason};
+
+
+/// Never write anything.
+struct SilentProtocol;
+
+impl Protocol for SilentProtocol {}
+
+
+struct SilentFactory;
+
+impl ServerFactory for SilentFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(SilentProtocol)
+    }
+}
+
+
+/// Record the calls of the loop, and hang up once woken up.
+struct WokenProtocol {
+    calls: Rc<RefCell<Vec<&'static str>>>,
+}
+
+impl Protocol for WokenProtocol {
+    fn connection_made(&mut self, _: &mut Transport) {
+        self.calls.borrow_mut().push("made");
+    }
+
+    fn wakeup(&mut self, transport: &mut Transport) {
+        self.calls.borrow_mut().push("wakeup");
+        transport.hang_up();
+    }
+
+    fn connection_lost(&mut self, _: Reason) {
+        self.calls.borrow_mut().push("lost");
+    }
+}
+
+
+#[test]
+fn test_wake_while_connecting() {
+    let mut rio = Rio::new();
+    let listener = rio.listen("127.0.0.1:0", Box::new(SilentFactory)).unwrap();
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let client = rio.connect(&listener.local_addr().to_string(),
+                             Box::new(WokenProtocol { calls: calls.clone() }))
+        .unwrap();
+
+    // the connect completes on the next poll, the protocol is not made yet
+    rio.wake(client).unwrap();
+    assert!(calls.borrow().is_empty());
+
+    // the server never writes, the connect is still detected
+    let start = Instant::now();
+    while calls.borrow().is_empty() && start.elapsed() < Duration::from_secs(5) {
+        rio.run_once();
+    }
+    assert_eq!(*calls.borrow(), vec!["made"]);
+
+    rio.wake(client).unwrap();
+    assert_eq!(*calls.borrow(), vec!["made", "wakeup", "lost"]);
+    assert!(!rio.contains(client));
+}
@@ -0,0 +1,141 @@
+extern crate janeiro;
+
+use std::cell::Cell;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+
+use janeiro::{Rio, Transport, ServerFactory, Protocol, Reason};
+
+
+/// Send a large payload, shared by every connection, then hang up.
+struct BroadcastProtocol {
+    payload: Arc<[u8]>,
+    closed: Rc<Cell<usize>>,
+}
+
+impl Protocol for BroadcastProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"begin\n");
+        transport.write_shared(self.payload.clone());
+        transport.write_owned(b"end\n".to_vec());
+        transport.hang_up();
+    }
+
+    fn connection_lost(&mut self, reason: Reason) {
+        assert_eq!(reason, Reason::HangUp);
+        self.closed.set(self.closed.get() + 1);
+    }
+}
+
+
+struct BroadcastFactory {
+    payload: Arc<[u8]>,
+    closed: Rc<Cell<usize>>,
+}
+
+impl ServerFactory for BroadcastFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(BroadcastProtocol {
+            payload: self.payload.clone(),
+            closed: self.closed.clone(),
+        })
+    }
+}
+
+
+#[test]
+fn test_partial_writes() {
+    let payload = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let shared: Arc<[u8]> = Arc::from(&payload[..]);
+    let closed = Rc::new(Cell::new(0));
+    let mut rio = Rio::new();
+    let listener = rio.listen("127.0.0.1:0", Box::new(BroadcastFactory {
+        payload: shared.clone(),
+        closed: closed.clone(),
+    })).unwrap();
+    let addr = listener.local_addr();
+
+    let readers = (0..3).map(|_| {
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        })
+    }).collect::<Vec<_>>();
+
+    rio.run_until(&|_: &Rio| -> bool { closed.get() < 3 });
+    // the payload is not copied, and released once written.
+    assert_eq!(Arc::strong_count(&shared), 2);
+    for reader in readers {
+        let received = reader.join().unwrap();
+        assert_eq!(received.len(), payload.len() + 10);
+        assert_eq!(&received[..6], b"begin\n");
+        assert!(received[6..payload.len() + 6] == payload[..]);
+        assert_eq!(&received[payload.len() + 6..], b"end\n");
+    }
+}
+
+
+/// Send a header, a region of a file, then a trailer.
+struct FileProtocol {
+    file: Option<File>,
+    len: usize,
+}
+
+impl Protocol for FileProtocol {
+    fn connection_made(&mut self, transport: &mut Transport) {
+        transport.write(b"begin\n");
+        transport.send_file(self.file.take().unwrap(), 1, self.len - 2);
+        transport.write(b"end\n");
+        transport.hang_up();
+    }
+}
+
+
+struct FileFactory {
+    path: PathBuf,
+    len: usize,
+}
+
+impl ServerFactory for FileFactory {
+    fn build_protocol(&self) -> Box<dyn Protocol> {
+        Box::new(FileProtocol {
+            file: Some(File::open(&self.path).unwrap()),
+            len: self.len,
+        })
+    }
+}
+
+
+#[test]
+fn test_send_file() {
+    let content = (0..2 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    let path = env::temp_dir().join(format!("janeiro-test-send-file-{}", process::id()));
+    File::create(&path).unwrap().write_all(&content[..]).unwrap();
+
+    let mut rio = Rio::new();
+    let listener = rio.listen("127.0.0.1:0", Box::new(FileFactory { path: path.clone(), len: content.len() })).unwrap();
+    let addr = listener.local_addr();
+    let reader = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        received
+    });
+    rio.run_until(&|_: &Rio| -> bool { !reader.is_finished() });
+    fs::remove_file(&path).unwrap();
+
+    let received = reader.join().unwrap();
+    assert_eq!(received.len(), content.len() - 2 + 10);
+    assert_eq!(&received[..6], b"begin\n");
+    assert!(received[6..content.len() + 4] == content[1..content.len() - 1]);
+    assert_eq!(&received[content.len() + 4..], b"end\n");
+}